@@ -0,0 +1,233 @@
+//! Memory map of the emulated CH32V307
+
+use crate::mmio::{
+    Flash, Rcc, SysTick, Watchdogs, ESIG_BASE, EXTEND_REGISTERS, FLASH_REGISTERS, IWDG_REGISTERS,
+    OB_BASE, OB_SIZE, RCC_REGISTERS, SYSTICK_REGISTERS, WWDG_REGISTERS,
+};
+
+/// Size of the address range of each peripheral
+const PERIPHERAL_SIZE: u32 = 0x400;
+/// FLACAP and the 96 bit unique ID
+const ESIG_SIZE: u32 = 0x10;
+/// Main flash capacity of the CH32V307
+pub const CAPACITY: u32 = 480 * 1024;
+
+/// RAM and everything memory mapped the loader may touch
+///
+/// Anything not mapped faults, like a bus error on the real chip.
+pub struct Bus {
+    ram_base: u32,
+    pub ram: Vec<u8>,
+    pub flash: Flash,
+    pub rcc: Rcc,
+    pub extend_ctr: u32,
+    pub systick: SysTick,
+    pub watchdogs: Watchdogs,
+}
+
+impl Bus {
+    /// `ram_size` bytes of RAM at `ram_base`, next to an erased flash of [`CAPACITY`] bytes
+    pub fn new(ram_base: u32, ram_size: u32) -> Self {
+        Self {
+            ram_base,
+            ram: vec![0; ram_size as usize],
+            flash: Flash::new(CAPACITY),
+            rcc: Rcc::new(),
+            extend_ctr: 0,
+            systick: SysTick::new(),
+            watchdogs: Watchdogs::default(),
+        }
+    }
+
+    /// Offset of the `len` bytes at `address` into RAM
+    fn ram_offset(&self, address: u32, len: u32) -> Option<usize> {
+        let offset = address.checked_sub(self.ram_base)?;
+        let end = offset.checked_add(len)?;
+        (end as usize <= self.ram.len()).then_some(offset as usize)
+    }
+
+    /// Copy `data` into RAM at `address`
+    pub fn load(&mut self, address: u32, data: &[u8]) -> Option<()> {
+        let offset = self.ram_offset(address, data.len() as u32)?;
+        self.ram[offset..offset + data.len()].copy_from_slice(data);
+        Some(())
+    }
+
+    /// Read `len` bytes of RAM, flash or option bytes
+    pub fn read_bytes(&mut self, address: u32, len: u32) -> Option<Vec<u8>> {
+        (0..len)
+            .map(|index| Some(self.read(address.checked_add(index)?, 1)? as u8))
+            .collect()
+    }
+
+    /// Read `size` bytes at `address`, little-endian
+    pub fn read(&mut self, address: u32, size: u32) -> Option<u32> {
+        if let Some(offset) = self.ram_offset(address, size) {
+            return Some(little_endian(&self.ram[offset..offset + size as usize]));
+        }
+        if let Some(offset) = self.flash.offset(address) {
+            let bytes = self.flash.memory.get(offset..offset + size as usize)?;
+            return Some(little_endian(bytes));
+        }
+        if let Some(offset) = range_offset(address, size, OB_BASE, OB_SIZE) {
+            return Some(little_endian(
+                &self.flash.option_bytes[offset as usize..(offset + size) as usize],
+            ));
+        }
+        if let Some(offset) = range_offset(address, size, ESIG_BASE, ESIG_SIZE) {
+            let mut esig = [0; ESIG_SIZE as usize];
+            esig[..2].copy_from_slice(&self.flash.capacity_kb().to_le_bytes());
+            return Some(little_endian(
+                &esig[offset as usize..(offset + size) as usize],
+            ));
+        }
+
+        let (base, offset) = register(address, size)?;
+        match base {
+            FLASH_REGISTERS => self.flash.read(offset),
+            RCC_REGISTERS => self.rcc.read(offset),
+            EXTEND_REGISTERS if offset == 0 => Some(self.extend_ctr),
+            IWDG_REGISTERS => self.watchdogs.read_iwdg(offset),
+            WWDG_REGISTERS => self.watchdogs.read_wwdg(offset),
+            SYSTICK_REGISTERS => self.systick.read(offset),
+            _ => None,
+        }
+    }
+
+    /// Write the lowest `size` bytes of `value` to `address`
+    pub fn write(&mut self, address: u32, size: u32, value: u32) -> Option<()> {
+        if let Some(offset) = self.ram_offset(address, size) {
+            let bytes = &value.to_le_bytes()[..size as usize];
+            self.ram[offset..offset + size as usize].copy_from_slice(bytes);
+            return Some(());
+        }
+        if self.flash.offset(address).is_some()
+            || range_offset(address, size, OB_BASE, OB_SIZE).is_some()
+        {
+            return self.flash.write_memory(address, size, value);
+        }
+
+        let (base, offset) = register(address, size)?;
+        match base {
+            FLASH_REGISTERS => self.flash.write(offset, value),
+            RCC_REGISTERS => self.rcc.write(offset, value),
+            EXTEND_REGISTERS if offset == 0 => {
+                self.extend_ctr = value;
+                Some(())
+            }
+            IWDG_REGISTERS => self.watchdogs.write_iwdg(offset, value),
+            WWDG_REGISTERS => self.watchdogs.write_wwdg(offset, value),
+            SYSTICK_REGISTERS => self.systick.write(offset, value),
+            _ => None,
+        }
+    }
+
+    /// Let the time of one instruction pass
+    pub fn tick(&mut self) {
+        self.systick.tick();
+    }
+}
+
+fn little_endian(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| value << 8 | byte as u32)
+}
+
+/// Offset of the `size` bytes at `address` into the `len` bytes at `base`
+fn range_offset(address: u32, size: u32, base: u32, len: u32) -> Option<u32> {
+    let offset = address.checked_sub(base)?;
+    (offset < len && size <= len - offset).then_some(offset)
+}
+
+/// Peripheral base and register offset of a word access to `address`
+///
+/// All registers are 32 bits wide, smaller or misaligned accesses fault.
+fn register(address: u32, size: u32) -> Option<(u32, u32)> {
+    if size != 4 || address & 3 != 0 {
+        return None;
+    }
+    let base = [
+        FLASH_REGISTERS,
+        RCC_REGISTERS,
+        EXTEND_REGISTERS,
+        IWDG_REGISTERS,
+        WWDG_REGISTERS,
+        SYSTICK_REGISTERS,
+    ]
+    .into_iter()
+    .find(|&base| range_offset(address, size, base, PERIPHERAL_SIZE).is_some())?;
+
+    Some((base, address - base))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmio::{
+        CTLR_LOCK, CTLR_PER, CTLR_PG, CTLR_STRT, FLASH_BASE, FLASH_KEY1, FLASH_KEY2,
+    };
+
+    fn unlocked() -> Bus {
+        let mut bus = Bus::new(0x2000_0000, 0x1000);
+        for key in [FLASH_KEY1, FLASH_KEY2] {
+            bus.write(FLASH_REGISTERS + 0x04, 4, key).unwrap();
+        }
+        bus
+    }
+
+    #[test]
+    fn keys_unlock_the_controller() {
+        let mut bus = Bus::new(0x2000_0000, 0x1000);
+        assert_ne!(bus.read(FLASH_REGISTERS + 0x10, 4).unwrap() & CTLR_LOCK, 0);
+
+        assert_eq!(
+            unlocked().read(FLASH_REGISTERS + 0x10, 4).unwrap() & CTLR_LOCK,
+            0
+        );
+
+        // A wrong key keeps it locked until the next reset
+        for key in [FLASH_KEY2, FLASH_KEY1, FLASH_KEY2] {
+            bus.write(FLASH_REGISTERS + 0x04, 4, key).unwrap();
+        }
+        assert_ne!(bus.read(FLASH_REGISTERS + 0x10, 4).unwrap() & CTLR_LOCK, 0);
+    }
+
+    #[test]
+    fn programming_needs_the_program_mode() {
+        let mut bus = unlocked();
+        assert_eq!(bus.write(FLASH_BASE, 2, 0x1234), None);
+
+        bus.write(FLASH_REGISTERS + 0x10, 4, CTLR_PG).unwrap();
+        bus.write(FLASH_BASE, 2, 0x1234).unwrap();
+
+        assert_eq!(bus.read(FLASH_BASE, 4), Some(0xffff_1234));
+    }
+
+    #[test]
+    fn page_erase() {
+        let mut bus = unlocked();
+        bus.flash.memory[0x1000..0x2001].fill(0);
+
+        bus.write(FLASH_REGISTERS + 0x10, 4, CTLR_PER).unwrap();
+        bus.write(FLASH_REGISTERS + 0x14, 4, FLASH_BASE + 0x1010)
+            .unwrap();
+        bus.write(FLASH_REGISTERS + 0x10, 4, CTLR_PER | CTLR_STRT)
+            .unwrap();
+
+        assert!(bus.flash.memory[0x1000..0x2000].iter().all(|&b| b == 0xff));
+        assert_eq!(bus.flash.memory[0x2000], 0);
+        assert_eq!(bus.flash.operations, [(CTLR_PER, FLASH_BASE + 0x1010)]);
+    }
+
+    #[test]
+    fn unmapped_addresses_fault() {
+        let mut bus = Bus::new(0x2000_0000, 0x1000);
+
+        assert_eq!(bus.read(0x2000_1000, 4), None);
+        assert_eq!(bus.read(0x4000_0000, 4), None);
+        assert_eq!(bus.read(FLASH_REGISTERS + 0x10, 2), None);
+        assert_eq!(bus.read(ESIG_BASE, 2), Some(480));
+    }
+}
@@ -0,0 +1,914 @@
+//! RV32IMAC interpreter, with the machine mode CSRs the loader touches
+
+use crate::bus::Bus;
+use std::collections::HashMap;
+
+/// Reasons for the interpreter to stop
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The instruction at `pc` isn't part of RV32IMAC or Zicsr
+    IllegalInstruction { pc: u32, instruction: u32 },
+    /// Nothing is mapped at `address`, or it can't be accessed that way
+    Access { pc: u32, address: u32 },
+    /// Jump or branch to an odd address
+    MisalignedJump { pc: u32, target: u32 },
+    /// ECALL, the loader never makes one
+    Ecall { pc: u32 },
+}
+
+/// What an instruction did, besides changing registers and memory
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    Continue,
+    /// EBREAK, which ends a function call
+    Break,
+}
+
+/// Register-register and register-immediate operations
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Alu {
+    Add,
+    Sub,
+    Sll,
+    Slt,
+    Sltu,
+    Xor,
+    Srl,
+    Sra,
+    Or,
+    And,
+    Mul,
+    Mulh,
+    Mulhsu,
+    Mulhu,
+    Div,
+    Divu,
+    Rem,
+    Remu,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Condition {
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    Ltu,
+    Geu,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Amo {
+    Swap,
+    Add,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    Minu,
+    Maxu,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Csr {
+    Write,
+    Set,
+    Clear,
+}
+
+/// Decoded instruction, compressed ones map onto their 32 bit equivalent
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Instruction {
+    Lui {
+        rd: usize,
+        imm: u32,
+    },
+    Auipc {
+        rd: usize,
+        imm: u32,
+    },
+    Jal {
+        rd: usize,
+        offset: u32,
+    },
+    Jalr {
+        rd: usize,
+        rs1: usize,
+        offset: u32,
+    },
+    Branch {
+        condition: Condition,
+        rs1: usize,
+        rs2: usize,
+        offset: u32,
+    },
+    Load {
+        rd: usize,
+        rs1: usize,
+        offset: u32,
+        size: u32,
+        signed: bool,
+    },
+    Store {
+        rs1: usize,
+        rs2: usize,
+        offset: u32,
+        size: u32,
+    },
+    OpImm {
+        alu: Alu,
+        rd: usize,
+        rs1: usize,
+        imm: u32,
+    },
+    Op {
+        alu: Alu,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    },
+    LoadReserved {
+        rd: usize,
+        rs1: usize,
+    },
+    StoreConditional {
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    },
+    Amo {
+        amo: Amo,
+        rd: usize,
+        rs1: usize,
+        rs2: usize,
+    },
+    Csr {
+        csr: Csr,
+        rd: usize,
+        address: u16,
+        rs1: usize,
+        immediate: bool,
+    },
+    /// FENCE, FENCE.I and WFI, nothing to do for a single hart without interrupts
+    Nop,
+    Ecall,
+    Ebreak,
+}
+
+/// Hart state
+pub struct Cpu {
+    pub x: [u32; 32],
+    pub pc: u32,
+    /// Machine mode CSRs, anything that was never written reads as 0
+    pub csrs: HashMap<u16, u32>,
+    /// Address of the last LR.W, cleared by SC.W
+    reservation: Option<u32>,
+    /// Instructions retired
+    pub instructions: u64,
+}
+
+/// mcycle, minstret and their upper halves
+const CSR_MCYCLE: u16 = 0xb00;
+const CSR_MINSTRET: u16 = 0xb02;
+const CSR_MCYCLEH: u16 = 0xb80;
+const CSR_MINSTRETH: u16 = 0xb82;
+
+impl Cpu {
+    pub fn new() -> Self {
+        Self {
+            x: [0; 32],
+            pc: 0,
+            csrs: HashMap::new(),
+            reservation: None,
+            instructions: 0,
+        }
+    }
+
+    /// Execute the instruction at `pc`
+    pub fn step(&mut self, bus: &mut Bus) -> Result<Step, Fault> {
+        let pc = self.pc;
+        let access = |address| Fault::Access { pc, address };
+
+        let low = bus.read(pc, 2).ok_or(access(pc))?;
+        let (instruction, length) = if low & 0b11 == 0b11 {
+            let high = bus.read(pc + 2, 2).ok_or(access(pc + 2))?;
+            let word = high << 16 | low;
+            let illegal = Fault::IllegalInstruction {
+                pc,
+                instruction: word,
+            };
+            (decode(word).ok_or(illegal)?, 4)
+        } else {
+            let illegal = Fault::IllegalInstruction {
+                pc,
+                instruction: low,
+            };
+            (decode_compressed(low as u16).ok_or(illegal)?, 2)
+        };
+
+        let mut next = pc.wrapping_add(length);
+        let mut step = Step::Continue;
+        match instruction {
+            Instruction::Lui { rd, imm } => self.set(rd, imm),
+            Instruction::Auipc { rd, imm } => self.set(rd, pc.wrapping_add(imm)),
+            Instruction::Jal { rd, offset } => {
+                next = self.jump(pc, pc.wrapping_add(offset))?;
+                self.set(rd, pc.wrapping_add(length));
+            }
+            Instruction::Jalr { rd, rs1, offset } => {
+                next = self.jump(pc, self.x[rs1].wrapping_add(offset) & !1)?;
+                self.set(rd, pc.wrapping_add(length));
+            }
+            Instruction::Branch {
+                condition,
+                rs1,
+                rs2,
+                offset,
+            } => {
+                let (a, b) = (self.x[rs1], self.x[rs2]);
+                let taken = match condition {
+                    Condition::Eq => a == b,
+                    Condition::Ne => a != b,
+                    Condition::Lt => (a as i32) < (b as i32),
+                    Condition::Ge => (a as i32) >= (b as i32),
+                    Condition::Ltu => a < b,
+                    Condition::Geu => a >= b,
+                };
+                if taken {
+                    next = self.jump(pc, pc.wrapping_add(offset))?;
+                }
+            }
+            Instruction::Load {
+                rd,
+                rs1,
+                offset,
+                size,
+                signed,
+            } => {
+                let address = self.x[rs1].wrapping_add(offset);
+                let value = bus.read(address, size).ok_or(access(address))?;
+                let value = match (size, signed) {
+                    (1, true) => value as u8 as i8 as u32,
+                    (2, true) => value as u16 as i16 as u32,
+                    _ => value,
+                };
+                self.set(rd, value);
+            }
+            Instruction::Store {
+                rs1,
+                rs2,
+                offset,
+                size,
+            } => {
+                let address = self.x[rs1].wrapping_add(offset);
+                bus.write(address, size, self.x[rs2])
+                    .ok_or(access(address))?;
+            }
+            Instruction::OpImm { alu, rd, rs1, imm } => self.set(rd, alu.apply(self.x[rs1], imm)),
+            Instruction::Op { alu, rd, rs1, rs2 } => {
+                self.set(rd, alu.apply(self.x[rs1], self.x[rs2]))
+            }
+            Instruction::LoadReserved { rd, rs1 } => {
+                let address = self.x[rs1];
+                let value = bus.read(address, 4).ok_or(access(address))?;
+                self.reservation = Some(address);
+                self.set(rd, value);
+            }
+            Instruction::StoreConditional { rd, rs1, rs2 } => {
+                let address = self.x[rs1];
+                if self.reservation.take() == Some(address) {
+                    bus.write(address, 4, self.x[rs2]).ok_or(access(address))?;
+                    self.set(rd, 0);
+                } else {
+                    self.set(rd, 1);
+                }
+            }
+            Instruction::Amo { amo, rd, rs1, rs2 } => {
+                let address = self.x[rs1];
+                let old = bus.read(address, 4).ok_or(access(address))?;
+                let operand = self.x[rs2];
+                let new = match amo {
+                    Amo::Swap => operand,
+                    Amo::Add => old.wrapping_add(operand),
+                    Amo::Xor => old ^ operand,
+                    Amo::And => old & operand,
+                    Amo::Or => old | operand,
+                    Amo::Min => (old as i32).min(operand as i32) as u32,
+                    Amo::Max => (old as i32).max(operand as i32) as u32,
+                    Amo::Minu => old.min(operand),
+                    Amo::Maxu => old.max(operand),
+                };
+                bus.write(address, 4, new).ok_or(access(address))?;
+                self.set(rd, old);
+            }
+            Instruction::Csr {
+                csr,
+                rd,
+                address,
+                rs1,
+                immediate,
+            } => {
+                let old = self.read_csr(address);
+                let operand = if immediate { rs1 as u32 } else { self.x[rs1] };
+                // CSRRS and CSRRC with x0 or a zero immediate only read
+                let writes = csr == Csr::Write || rs1 != 0;
+                if writes {
+                    let new = match csr {
+                        Csr::Write => operand,
+                        Csr::Set => old | operand,
+                        Csr::Clear => old & !operand,
+                    };
+                    self.csrs.insert(address, new);
+                }
+                self.set(rd, old);
+            }
+            Instruction::Nop => (),
+            Instruction::Ecall => return Err(Fault::Ecall { pc }),
+            Instruction::Ebreak => step = Step::Break,
+        }
+
+        self.pc = next;
+        self.instructions += 1;
+        Ok(step)
+    }
+
+    fn set(&mut self, rd: usize, value: u32) {
+        if rd != 0 {
+            self.x[rd] = value;
+        }
+    }
+
+    fn jump(&self, pc: u32, target: u32) -> Result<u32, Fault> {
+        // With the C extension, only odd targets are misaligned
+        if target & 1 != 0 {
+            return Err(Fault::MisalignedJump { pc, target });
+        }
+        Ok(target)
+    }
+
+    fn read_csr(&self, address: u16) -> u32 {
+        match address {
+            CSR_MCYCLE | CSR_MINSTRET => self.instructions as u32,
+            CSR_MCYCLEH | CSR_MINSTRETH => (self.instructions >> 32) as u32,
+            _ => self.csrs.get(&address).copied().unwrap_or(0),
+        }
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Alu {
+    fn apply(self, a: u32, b: u32) -> u32 {
+        let shamt = b & 0x1f;
+        match self {
+            Alu::Add => a.wrapping_add(b),
+            Alu::Sub => a.wrapping_sub(b),
+            Alu::Sll => a << shamt,
+            Alu::Slt => ((a as i32) < (b as i32)) as u32,
+            Alu::Sltu => (a < b) as u32,
+            Alu::Xor => a ^ b,
+            Alu::Srl => a >> shamt,
+            Alu::Sra => ((a as i32) >> shamt) as u32,
+            Alu::Or => a | b,
+            Alu::And => a & b,
+            Alu::Mul => a.wrapping_mul(b),
+            Alu::Mulh => ((a as i32 as i64 * b as i32 as i64) >> 32) as u32,
+            Alu::Mulhsu => ((a as i32 as i64 * b as i64) >> 32) as u32,
+            Alu::Mulhu => ((a as u64 * b as u64) >> 32) as u32,
+            Alu::Div => match (a as i32, b as i32) {
+                (_, 0) => u32::MAX,
+                (i32::MIN, -1) => a,
+                (a, b) => (a / b) as u32,
+            },
+            Alu::Divu => a.checked_div(b).unwrap_or(u32::MAX),
+            Alu::Rem => match (a as i32, b as i32) {
+                (_, 0) => a,
+                (i32::MIN, -1) => 0,
+                (a, b) => (a % b) as u32,
+            },
+            Alu::Remu => a.checked_rem(b).unwrap_or(a),
+        }
+    }
+}
+
+/// Bits `high` down to `low` of `value`
+fn bits(value: u32, high: u32, low: u32) -> u32 {
+    (value >> low) & ((1 << (high - low + 1)) - 1)
+}
+
+/// Sign extend the lowest `width` bits of `value`
+fn sign_extend(value: u32, width: u32) -> u32 {
+    let shift = 32 - width;
+    (((value << shift) as i32) >> shift) as u32
+}
+
+fn decode(word: u32) -> Option<Instruction> {
+    let rd = bits(word, 11, 7) as usize;
+    let rs1 = bits(word, 19, 15) as usize;
+    let rs2 = bits(word, 24, 20) as usize;
+    let funct3 = bits(word, 14, 12);
+    let funct7 = bits(word, 31, 25);
+    let imm_i = sign_extend(bits(word, 31, 20), 12);
+    let imm_s = sign_extend(bits(word, 31, 25) << 5 | bits(word, 11, 7), 12);
+    let imm_b = sign_extend(
+        bits(word, 31, 31) << 12
+            | bits(word, 7, 7) << 11
+            | bits(word, 30, 25) << 5
+            | bits(word, 11, 8) << 1,
+        13,
+    );
+    let imm_u = word & 0xffff_f000;
+    let imm_j = sign_extend(
+        bits(word, 31, 31) << 20
+            | bits(word, 19, 12) << 12
+            | bits(word, 20, 20) << 11
+            | bits(word, 30, 21) << 1,
+        21,
+    );
+
+    let instruction = match bits(word, 6, 0) {
+        0b0110111 => Instruction::Lui { rd, imm: imm_u },
+        0b0010111 => Instruction::Auipc { rd, imm: imm_u },
+        0b1101111 => Instruction::Jal { rd, offset: imm_j },
+        0b1100111 if funct3 == 0 => Instruction::Jalr {
+            rd,
+            rs1,
+            offset: imm_i,
+        },
+        0b1100011 => Instruction::Branch {
+            condition: match funct3 {
+                0b000 => Condition::Eq,
+                0b001 => Condition::Ne,
+                0b100 => Condition::Lt,
+                0b101 => Condition::Ge,
+                0b110 => Condition::Ltu,
+                0b111 => Condition::Geu,
+                _ => return None,
+            },
+            rs1,
+            rs2,
+            offset: imm_b,
+        },
+        0b0000011 => {
+            let (size, signed) = match funct3 {
+                0b000 => (1, true),
+                0b001 => (2, true),
+                0b010 => (4, false),
+                0b100 => (1, false),
+                0b101 => (2, false),
+                _ => return None,
+            };
+            Instruction::Load {
+                rd,
+                rs1,
+                offset: imm_i,
+                size,
+                signed,
+            }
+        }
+        0b0100011 => Instruction::Store {
+            rs1,
+            rs2,
+            offset: imm_s,
+            size: match funct3 {
+                0b000 => 1,
+                0b001 => 2,
+                0b010 => 4,
+                _ => return None,
+            },
+        },
+        0b0010011 => {
+            let alu = match (funct3, funct7) {
+                (0b000, _) => Alu::Add,
+                (0b010, _) => Alu::Slt,
+                (0b011, _) => Alu::Sltu,
+                (0b100, _) => Alu::Xor,
+                (0b110, _) => Alu::Or,
+                (0b111, _) => Alu::And,
+                (0b001, 0b0000000) => Alu::Sll,
+                (0b101, 0b0000000) => Alu::Srl,
+                (0b101, 0b0100000) => Alu::Sra,
+                _ => return None,
+            };
+            // The shifts take their amount from rs2, which is all that's left of the immediate
+            let imm = match alu {
+                Alu::Sll | Alu::Srl | Alu::Sra => rs2 as u32,
+                _ => imm_i,
+            };
+            Instruction::OpImm { alu, rd, rs1, imm }
+        }
+        0b0110011 => {
+            let alu = match (funct7, funct3) {
+                (0b0000000, 0b000) => Alu::Add,
+                (0b0100000, 0b000) => Alu::Sub,
+                (0b0000000, 0b001) => Alu::Sll,
+                (0b0000000, 0b010) => Alu::Slt,
+                (0b0000000, 0b011) => Alu::Sltu,
+                (0b0000000, 0b100) => Alu::Xor,
+                (0b0000000, 0b101) => Alu::Srl,
+                (0b0100000, 0b101) => Alu::Sra,
+                (0b0000000, 0b110) => Alu::Or,
+                (0b0000000, 0b111) => Alu::And,
+                (0b0000001, 0b000) => Alu::Mul,
+                (0b0000001, 0b001) => Alu::Mulh,
+                (0b0000001, 0b010) => Alu::Mulhsu,
+                (0b0000001, 0b011) => Alu::Mulhu,
+                (0b0000001, 0b100) => Alu::Div,
+                (0b0000001, 0b101) => Alu::Divu,
+                (0b0000001, 0b110) => Alu::Rem,
+                (0b0000001, 0b111) => Alu::Remu,
+                _ => return None,
+            };
+            Instruction::Op { alu, rd, rs1, rs2 }
+        }
+        0b0101111 if funct3 == 0b010 => match bits(word, 31, 27) {
+            0b00010 if rs2 == 0 => Instruction::LoadReserved { rd, rs1 },
+            0b00011 => Instruction::StoreConditional { rd, rs1, rs2 },
+            funct5 => Instruction::Amo {
+                amo: match funct5 {
+                    0b00001 => Amo::Swap,
+                    0b00000 => Amo::Add,
+                    0b00100 => Amo::Xor,
+                    0b01100 => Amo::And,
+                    0b01000 => Amo::Or,
+                    0b10000 => Amo::Min,
+                    0b10100 => Amo::Max,
+                    0b11000 => Amo::Minu,
+                    0b11100 => Amo::Maxu,
+                    _ => return None,
+                },
+                rd,
+                rs1,
+                rs2,
+            },
+        },
+        0b0001111 => Instruction::Nop,
+        0b1110011 => match funct3 {
+            0b000 => match word {
+                0x0000_0073 => Instruction::Ecall,
+                0x0010_0073 => Instruction::Ebreak,
+                0x1050_0073 => Instruction::Nop,
+                _ => return None,
+            },
+            0b100 => return None,
+            // Writes to read-only CSRs are illegal, that's what UNIMP relies on
+            _ if bits(word, 31, 30) == 0b11 && (funct3 & 0b11 == 0b01 || rs1 != 0) => return None,
+            _ => Instruction::Csr {
+                csr: match funct3 & 0b11 {
+                    0b01 => Csr::Write,
+                    0b10 => Csr::Set,
+                    _ => Csr::Clear,
+                },
+                rd,
+                address: bits(word, 31, 20) as u16,
+                rs1,
+                immediate: funct3 & 0b100 != 0,
+            },
+        },
+        _ => return None,
+    };
+
+    Some(instruction)
+}
+
+fn decode_compressed(half_word: u16) -> Option<Instruction> {
+    let word = half_word as u32;
+    // Registers x8 to x15 in the three bit fields
+    let rd_prime = bits(word, 4, 2) as usize + 8;
+    let rs1_prime = bits(word, 9, 7) as usize + 8;
+    let rd = bits(word, 11, 7) as usize;
+    let rs2 = bits(word, 6, 2) as usize;
+    let imm6 = sign_extend(bits(word, 12, 12) << 5 | bits(word, 6, 2), 6);
+    let shamt = bits(word, 6, 2);
+    let offset_lw = bits(word, 12, 10) << 3 | bits(word, 6, 6) << 2 | bits(word, 5, 5) << 6;
+    let offset_j = sign_extend(
+        bits(word, 12, 12) << 11
+            | bits(word, 11, 11) << 4
+            | bits(word, 10, 9) << 8
+            | bits(word, 8, 8) << 10
+            | bits(word, 7, 7) << 6
+            | bits(word, 6, 6) << 7
+            | bits(word, 5, 3) << 1
+            | bits(word, 2, 2) << 5,
+        12,
+    );
+    let offset_b = sign_extend(
+        bits(word, 12, 12) << 8
+            | bits(word, 11, 10) << 3
+            | bits(word, 6, 5) << 6
+            | bits(word, 4, 3) << 1
+            | bits(word, 2, 2) << 5,
+        9,
+    );
+
+    let instruction = match (bits(word, 1, 0), bits(word, 15, 13)) {
+        // C.ADDI4SPN
+        (0b00, 0b000) => {
+            let imm = bits(word, 12, 11) << 4
+                | bits(word, 10, 7) << 6
+                | bits(word, 6, 6) << 2
+                | bits(word, 5, 5) << 3;
+            if imm == 0 {
+                return None;
+            }
+            Instruction::OpImm {
+                alu: Alu::Add,
+                rd: rd_prime,
+                rs1: 2,
+                imm,
+            }
+        }
+        // C.LW
+        (0b00, 0b010) => Instruction::Load {
+            rd: rd_prime,
+            rs1: rs1_prime,
+            offset: offset_lw,
+            size: 4,
+            signed: false,
+        },
+        // C.SW
+        (0b00, 0b110) => Instruction::Store {
+            rs1: rs1_prime,
+            rs2: rd_prime,
+            offset: offset_lw,
+            size: 4,
+        },
+        // C.ADDI, C.NOP
+        (0b01, 0b000) => Instruction::OpImm {
+            alu: Alu::Add,
+            rd,
+            rs1: rd,
+            imm: imm6,
+        },
+        // C.JAL
+        (0b01, 0b001) => Instruction::Jal {
+            rd: 1,
+            offset: offset_j,
+        },
+        // C.LI
+        (0b01, 0b010) => Instruction::OpImm {
+            alu: Alu::Add,
+            rd,
+            rs1: 0,
+            imm: imm6,
+        },
+        // C.ADDI16SP
+        (0b01, 0b011) if rd == 2 => {
+            let imm = sign_extend(
+                bits(word, 12, 12) << 9
+                    | bits(word, 6, 6) << 4
+                    | bits(word, 5, 5) << 6
+                    | bits(word, 4, 3) << 7
+                    | bits(word, 2, 2) << 5,
+                10,
+            );
+            if imm == 0 {
+                return None;
+            }
+            Instruction::OpImm {
+                alu: Alu::Add,
+                rd: 2,
+                rs1: 2,
+                imm,
+            }
+        }
+        // C.LUI
+        (0b01, 0b011) => {
+            if imm6 == 0 {
+                return None;
+            }
+            Instruction::Lui {
+                rd,
+                imm: imm6 << 12,
+            }
+        }
+        (0b01, 0b100) => {
+            let rd = rs1_prime;
+            match bits(word, 11, 10) {
+                0b00 | 0b01 if bits(word, 12, 12) != 0 => return None,
+                0b00 => Instruction::OpImm {
+                    alu: Alu::Srl,
+                    rd,
+                    rs1: rd,
+                    imm: shamt,
+                },
+                0b01 => Instruction::OpImm {
+                    alu: Alu::Sra,
+                    rd,
+                    rs1: rd,
+                    imm: shamt,
+                },
+                0b10 => Instruction::OpImm {
+                    alu: Alu::And,
+                    rd,
+                    rs1: rd,
+                    imm: imm6,
+                },
+                _ => Instruction::Op {
+                    alu: match (bits(word, 12, 12), bits(word, 6, 5)) {
+                        (0, 0b00) => Alu::Sub,
+                        (0, 0b01) => Alu::Xor,
+                        (0, 0b10) => Alu::Or,
+                        (0, 0b11) => Alu::And,
+                        _ => return None,
+                    },
+                    rd,
+                    rs1: rd,
+                    rs2: rd_prime,
+                },
+            }
+        }
+        // C.J
+        (0b01, 0b101) => Instruction::Jal {
+            rd: 0,
+            offset: offset_j,
+        },
+        // C.BEQZ, C.BNEZ
+        (0b01, 0b110) | (0b01, 0b111) => Instruction::Branch {
+            condition: if bits(word, 13, 13) == 0 {
+                Condition::Eq
+            } else {
+                Condition::Ne
+            },
+            rs1: rs1_prime,
+            rs2: 0,
+            offset: offset_b,
+        },
+        // C.SLLI
+        (0b10, 0b000) if bits(word, 12, 12) == 0 => Instruction::OpImm {
+            alu: Alu::Sll,
+            rd,
+            rs1: rd,
+            imm: shamt,
+        },
+        // C.LWSP
+        (0b10, 0b010) if rd != 0 => Instruction::Load {
+            rd,
+            rs1: 2,
+            offset: bits(word, 12, 12) << 5 | bits(word, 6, 4) << 2 | bits(word, 3, 2) << 6,
+            size: 4,
+            signed: false,
+        },
+        (0b10, 0b100) => match (bits(word, 12, 12), rd, rs2) {
+            // C.JR
+            (0, 0, 0) => return None,
+            (0, rs1, 0) => Instruction::Jalr {
+                rd: 0,
+                rs1,
+                offset: 0,
+            },
+            // C.MV
+            (0, rd, rs2) => Instruction::Op {
+                alu: Alu::Add,
+                rd,
+                rs1: 0,
+                rs2,
+            },
+            (_, 0, 0) => Instruction::Ebreak,
+            // C.JALR
+            (_, rs1, 0) => Instruction::Jalr {
+                rd: 1,
+                rs1,
+                offset: 0,
+            },
+            // C.ADD
+            (_, rd, rs2) => Instruction::Op {
+                alu: Alu::Add,
+                rd,
+                rs1: rd,
+                rs2,
+            },
+        },
+        // C.SWSP
+        (0b10, 0b110) => Instruction::Store {
+            rs1: 2,
+            rs2,
+            offset: bits(word, 12, 9) << 2 | bits(word, 8, 7) << 6,
+            size: 4,
+        },
+        _ => return None,
+    };
+
+    Some(instruction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `code` from the start of RAM until it hits EBREAK
+    fn run(code: &[u8], setup: impl FnOnce(&mut Cpu)) -> Cpu {
+        let mut bus = Bus::new(0x2000_0000, 0x1000);
+        bus.load(0x2000_0000, code).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.pc = 0x2000_0000;
+        setup(&mut cpu);
+        while cpu.step(&mut bus).unwrap() == Step::Continue {}
+        cpu
+    }
+
+    #[test]
+    fn arithmetic() {
+        let cpu = run(
+            &[
+                0x13, 0x05, 0x15, 0x00, // addi a0, a0, 1
+                0x05, 0x05, // c.addi a0, 1
+                0x33, 0x06, 0xb5, 0x02, // mul a2, a0, a1
+                0xb3, 0x46, 0xb5, 0x02, // div a3, a0, a1
+                0x33, 0x77, 0xa5, 0x02, // remu a4, a0, a0
+                0x02, 0x90, // c.ebreak
+            ],
+            |cpu| {
+                cpu.x[10] = 40;
+                cpu.x[11] = (-3i32) as u32;
+            },
+        );
+
+        assert_eq!(cpu.x[10], 42);
+        assert_eq!(cpu.x[12] as i32, -126);
+        assert_eq!(cpu.x[13] as i32, -14);
+        assert_eq!(cpu.x[14], 0);
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let cpu = run(
+            &[
+                0xb3, 0x46, 0xb5, 0x02, // div a3, a0, a1
+                0x33, 0x67, 0xb5, 0x02, // rem a4, a0, a1
+                0x73, 0x00, 0x10, 0x00, // ebreak
+            ],
+            |cpu| cpu.x[10] = 7,
+        );
+
+        assert_eq!(cpu.x[13], u32::MAX);
+        assert_eq!(cpu.x[14], 7);
+    }
+
+    #[test]
+    fn loop_with_compressed_branch() {
+        let cpu = run(
+            &[
+                0x7d, 0x15, // c.addi a0, -1
+                0x05, 0x06, // c.addi a2, 1
+                0x75, 0xfd, // c.bnez a0, -4
+                0x02, 0x90, // c.ebreak
+            ],
+            |cpu| cpu.x[10] = 5,
+        );
+
+        assert_eq!(cpu.x[10], 0);
+        assert_eq!(cpu.x[12], 5);
+    }
+
+    #[test]
+    fn memory_and_atomics() {
+        let cpu = run(
+            &[
+                0x37, 0x15, 0x00, 0x20, // lui a0, 0x20001
+                0x13, 0x05, 0x05, 0x80, // addi a0, a0, -2048
+                0x93, 0x05, 0xf0, 0xff, // li a1, -1
+                0x23, 0x00, 0xb5, 0x00, // sb a1, 0(a0)
+                0x03, 0x46, 0x05, 0x00, // lbu a2, 0(a0)
+                0x83, 0x06, 0x05, 0x00, // lb a3, 0(a0)
+                0x2f, 0x27, 0x05, 0x10, // lr.w a4, (a0)
+                0xaf, 0x27, 0xb5, 0x18, // sc.w a5, a1, (a0)
+                0x2f, 0x28, 0xb5, 0x00, // amoadd.w a6, a1, (a0)
+                0x83, 0x28, 0x05, 0x00, // lw a7, 0(a0)
+                0x02, 0x90, // c.ebreak
+            ],
+            |_| (),
+        );
+
+        assert_eq!(cpu.x[12], 0xff);
+        assert_eq!(cpu.x[13], u32::MAX);
+        assert_eq!(cpu.x[14], 0xff);
+        // The reservation held, so the store went through
+        assert_eq!(cpu.x[15], 0);
+        assert_eq!(cpu.x[16], u32::MAX);
+        assert_eq!(cpu.x[17], u32::MAX - 1);
+    }
+
+    #[test]
+    fn csrs() {
+        let cpu = run(
+            &[
+                0x73, 0x25, 0x00, 0x30, // csrr a0, mstatus
+                0x73, 0x60, 0x04, 0x30, // csrsi mstatus, 8
+                0xf3, 0x75, 0x04, 0x30, // csrrci a1, mstatus, 8
+                0x73, 0x26, 0x00, 0x30, // csrr a2, mstatus
+                0x02, 0x90, // c.ebreak
+            ],
+            |_| (),
+        );
+
+        assert_eq!(cpu.x[10], 0);
+        assert_eq!(cpu.x[11], 8);
+        assert_eq!(cpu.x[12], 0);
+    }
+}
@@ -0,0 +1,124 @@
+//! Just enough of ELF32 to find the sections and symbols of a flash loader
+
+use crate::Error;
+
+/// EM_RISCV
+const MACHINE_RISCV: u16 = 243;
+/// SHT_SYMTAB
+const SECTION_SYMTAB: u32 = 2;
+/// SHT_NOBITS, a section without content in the file, like .bss
+const SECTION_NOBITS: u32 = 8;
+
+pub struct Section<'a> {
+    pub name: &'a str,
+    pub address: u32,
+    pub size: u32,
+    /// File content, `None` for sections that are zero filled when loaded
+    pub data: Option<&'a [u8]>,
+}
+
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    pub value: u32,
+}
+
+pub struct Elf<'a> {
+    pub sections: Vec<Section<'a>>,
+    pub symbols: Vec<Symbol<'a>>,
+}
+
+fn invalid(reason: &'static str) -> Error {
+    Error::Elf(reason)
+}
+
+fn bytes(file: &[u8], offset: u32, len: u32) -> Result<&[u8], Error> {
+    let start = offset as usize;
+    let end = start
+        .checked_add(len as usize)
+        .ok_or(invalid("truncated"))?;
+    file.get(start..end).ok_or(invalid("truncated"))
+}
+
+fn u16_at(file: &[u8], offset: u32) -> Result<u16, Error> {
+    let bytes = bytes(file, offset, 2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(file: &[u8], offset: u32) -> Result<u32, Error> {
+    let bytes = bytes(file, offset, 4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Zero terminated string at `offset` into the string table at `table`
+fn string(file: &[u8], table: u32, offset: u32) -> Result<&str, Error> {
+    let start = table.checked_add(offset).ok_or(invalid("truncated"))? as usize;
+    let tail = file.get(start..).ok_or(invalid("truncated"))?;
+    let len = tail
+        .iter()
+        .position(|&byte| byte == 0)
+        .ok_or(invalid("unterminated string"))?;
+    std::str::from_utf8(&tail[..len]).map_err(|_| invalid("name isn't UTF-8"))
+}
+
+impl<'a> Elf<'a> {
+    /// Parse a 32 bit little-endian RISC-V ELF file
+    pub fn parse(file: &'a [u8]) -> Result<Self, Error> {
+        if bytes(file, 0, 4)? != b"\x7fELF" {
+            return Err(invalid("not an ELF file"));
+        }
+        if bytes(file, 4, 2)? != [1, 1] {
+            return Err(invalid("not 32 bit little-endian"));
+        }
+        if u16_at(file, 0x12)? != MACHINE_RISCV {
+            return Err(invalid("not RISC-V"));
+        }
+
+        let section_headers = u32_at(file, 0x20)?;
+        let header_size = u16_at(file, 0x2e)? as u32;
+        let count = u16_at(file, 0x30)? as u32;
+        let names = u16_at(file, 0x32)? as u32;
+        let header = |index: u32| section_headers + index * header_size;
+        let names_offset = u32_at(file, header(names) + 0x10)?;
+
+        let mut sections = Vec::new();
+        let mut symbols = Vec::new();
+        for index in 0..count {
+            let base = header(index);
+            let kind = u32_at(file, base + 0x04)?;
+            let offset = u32_at(file, base + 0x10)?;
+            let size = u32_at(file, base + 0x14)?;
+
+            if kind == SECTION_SYMTAB {
+                let link = u32_at(file, base + 0x18)?;
+                let strings = u32_at(file, header(link) + 0x10)?;
+                for entry in (offset..offset + size).step_by(16) {
+                    let name = string(file, strings, u32_at(file, entry)?)?;
+                    if !name.is_empty() {
+                        let value = u32_at(file, entry + 0x04)?;
+                        symbols.push(Symbol { name, value });
+                    }
+                }
+            }
+
+            sections.push(Section {
+                name: string(file, names_offset, u32_at(file, base)?)?,
+                address: u32_at(file, base + 0x0c)?,
+                size,
+                data: if kind == SECTION_NOBITS {
+                    None
+                } else {
+                    Some(bytes(file, offset, size)?)
+                },
+            });
+        }
+
+        Ok(Self { sections, symbols })
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == name)
+            .map(|symbol| symbol.value)
+    }
+}
@@ -0,0 +1,179 @@
+//! Runs the built flash loader on an emulated CH32V307
+//!
+//! Like probe-rs, [`Emulator`] copies the PrgCode and PrgData sections of the loader into RAM,
+//! behind a header with an `ebreak`. Each function is called with its arguments in a0-a2 and
+//! the return address pointing at the header, so the emulation stops once it returns. The
+//! FLASH, RCC, EXTEND, SysTick and watchdog registers, the flash itself, the option bytes and
+//! the electronic signature are modelled in [`mmio`], anything else the loader touches faults.
+
+pub mod bus;
+pub mod cpu;
+pub mod elf;
+pub mod mmio;
+
+use bus::Bus;
+use cpu::{Cpu, Fault, Step};
+use elf::Elf;
+use std::collections::HashMap;
+use std::fmt;
+
+/// `ebreak`, the return address of every call
+const BREAKPOINT: u32 = 0x0010_0073;
+/// Size of the header in front of the loader
+const HEADER_SIZE: u32 = 4;
+/// RAM between the loader and the stack for the data of ProgramPage and Verify
+pub const BUFFER_SIZE: u32 = 0x1000;
+/// RAM reserved for the stack at its end
+pub const STACK_SIZE: u32 = 0x1000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The loader isn't a valid ELF file
+    Elf(&'static str),
+    /// Neither PrgCode nor PrgData were found
+    NoCode,
+    /// Loader, buffer and stack need more RAM than there is
+    DoesNotFit,
+    UnknownFunction(String),
+    /// The loader stopped at an `ebreak` other than the return address
+    Breakpoint {
+        pc: u32,
+    },
+    Fault(Fault),
+    /// The function didn't return within [`Emulator::instruction_limit`]
+    InstructionLimit,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Elf(reason) => write!(f, "invalid ELF file: {reason}"),
+            Error::NoCode => write!(f, "no PrgCode or PrgData section"),
+            Error::DoesNotFit => write!(f, "the loader doesn't fit into RAM"),
+            Error::UnknownFunction(name) => write!(f, "no function {name}"),
+            Error::Breakpoint { pc } => write!(f, "breakpoint at {pc:#010x}"),
+            Error::Fault(fault) => write!(f, "{fault:x?}"),
+            Error::InstructionLimit => write!(f, "the function didn't return"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A flash loader loaded into the RAM of an emulated chip
+pub struct Emulator {
+    pub cpu: Cpu,
+    pub bus: Bus,
+    /// Addresses of the loader's symbols, where they ended up in RAM
+    symbols: HashMap<String, u32>,
+    /// The `ebreak` every function returns to
+    return_address: u32,
+    /// Start of the RAM for the data of ProgramPage and Verify
+    buffer: u32,
+    stack_top: u32,
+    /// Instructions a call may take before it's aborted
+    pub instruction_limit: u64,
+}
+
+impl Emulator {
+    /// Load the loader in `elf` into `ram_size` bytes of RAM at `ram_base`
+    ///
+    /// The loader is placed at the start of RAM, its sections keep their relative positions.
+    /// The data buffer follows, the stack takes the end of RAM.
+    pub fn new(elf: &[u8], ram_base: u32, ram_size: u32) -> Result<Self, Error> {
+        let elf = Elf::parse(elf)?;
+        let mut bus = Bus::new(ram_base, ram_size);
+        let load_address = ram_base + HEADER_SIZE;
+
+        bus.load(ram_base, &BREAKPOINT.to_le_bytes())
+            .ok_or(Error::DoesNotFit)?;
+        let mut end = load_address;
+        let mut found = false;
+        for section in &elf.sections {
+            if section.name != "PrgCode" && section.name != "PrgData" {
+                continue;
+            }
+            found = true;
+            let address = load_address + section.address;
+            match section.data {
+                Some(data) => bus.load(address, data),
+                None => bus.load(address, &vec![0; section.size as usize]),
+            }
+            .ok_or(Error::DoesNotFit)?;
+            end = end.max(address + section.size);
+        }
+        if !found {
+            return Err(Error::NoCode);
+        }
+
+        let buffer = (end + 3) & !3;
+        let stack_top = ram_base + ram_size;
+        if buffer + BUFFER_SIZE + STACK_SIZE > stack_top {
+            return Err(Error::DoesNotFit);
+        }
+
+        let symbols = elf
+            .symbols
+            .iter()
+            .map(|symbol| (symbol.name.to_string(), load_address + symbol.value))
+            .collect();
+
+        Ok(Self {
+            cpu: Cpu::new(),
+            bus,
+            symbols,
+            return_address: ram_base,
+            buffer,
+            stack_top,
+            instruction_limit: 1_000_000_000,
+        })
+    }
+
+    /// Address a symbol of the loader was loaded to
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).copied()
+    }
+
+    /// Copy `data` into the data buffer, for ProgramPage and Verify
+    ///
+    /// `Return` - the address of the buffer.
+    pub fn write_buffer(&mut self, data: &[u8]) -> u32 {
+        assert!(data.len() as u32 <= BUFFER_SIZE, "data exceeds the buffer");
+        self.bus.load(self.buffer, data).unwrap();
+        self.buffer
+    }
+
+    /// Call the function `name` of the loader with `args` and run it until it returns
+    ///
+    /// `Return` - the content of a0.
+    pub fn call(&mut self, name: &str, args: &[u32]) -> Result<u32, Error> {
+        assert!(args.len() <= 8, "only arguments in registers are supported");
+        let entry = self
+            .symbol(name)
+            .ok_or_else(|| Error::UnknownFunction(name.to_string()))?;
+        let return_address = self.return_address;
+
+        self.cpu.pc = entry;
+        // ra, sp and a0-a7
+        self.cpu.x[1] = return_address;
+        self.cpu.x[2] = self.stack_top;
+        for (register, arg) in self.cpu.x[10..].iter_mut().zip(args) {
+            *register = *arg;
+        }
+
+        let limit = self.cpu.instructions + self.instruction_limit;
+        while self.cpu.instructions < limit {
+            let pc = self.cpu.pc;
+            let step = self.cpu.step(&mut self.bus).map_err(Error::Fault)?;
+            self.bus.tick();
+            if step == Step::Break {
+                if pc != return_address {
+                    return Err(Error::Breakpoint { pc });
+                }
+                return Ok(self.cpu.x[10]);
+            }
+        }
+
+        Err(Error::InstructionLimit)
+    }
+}
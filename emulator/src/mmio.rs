@@ -0,0 +1,519 @@
+//! Register level models of the peripherals the loader uses
+//!
+//! Each model covers one peripheral, `read` and `write` take the offset of a 32 bit register.
+//! Accesses the hardware would reject, like programming flash that isn't in a program mode,
+//! return `None` and stop the emulation with an access fault.
+
+/// Start of the main flash
+pub const FLASH_BASE: u32 = 0x0800_0000;
+/// Electronic signature, FLACAP followed by the unique ID
+pub const ESIG_BASE: u32 = 0x1FFF_F7E0;
+/// Option bytes
+pub const OB_BASE: u32 = 0x1FFF_F800;
+pub const OB_SIZE: u32 = 16;
+pub const FLASH_REGISTERS: u32 = 0x4002_2000;
+pub const RCC_REGISTERS: u32 = 0x4002_1000;
+pub const EXTEND_REGISTERS: u32 = 0x4002_3800;
+pub const IWDG_REGISTERS: u32 = 0x4000_3000;
+pub const WWDG_REGISTERS: u32 = 0x4000_2C00;
+pub const SYSTICK_REGISTERS: u32 = 0xE000_F000;
+
+pub const FLASH_KEY1: u32 = 0x4567_0123;
+pub const FLASH_KEY2: u32 = 0xCDEF_89AB;
+
+/// Value of an erased flash byte
+const EMPTY: u8 = 0xff;
+/// Flash covered by each of WPR bits 0 to 30, bit 31 covers everything above
+const WRP_GROUP_SIZE: u32 = 0x1000;
+/// Size of the fast programming page buffer
+const FAST_PAGE_SIZE: u32 = 256;
+
+// FLASH_CTLR
+pub const CTLR_PG: u32 = 1 << 0;
+pub const CTLR_PER: u32 = 1 << 1;
+pub const CTLR_MER: u32 = 1 << 2;
+pub const CTLR_OBPG: u32 = 1 << 4;
+pub const CTLR_OBER: u32 = 1 << 5;
+pub const CTLR_STRT: u32 = 1 << 6;
+pub const CTLR_LOCK: u32 = 1 << 7;
+pub const CTLR_OBWRE: u32 = 1 << 9;
+pub const CTLR_FLOCK: u32 = 1 << 15;
+pub const CTLR_FTPG: u32 = 1 << 16;
+pub const CTLR_FTER: u32 = 1 << 17;
+pub const CTLR_BUFLOAD: u32 = 1 << 18;
+pub const CTLR_BUFRST: u32 = 1 << 19;
+pub const CTLR_BER32: u32 = 1 << 23;
+/// Bits selecting an operation, at most one of them may be set
+const CTLR_MODES: u32 =
+    CTLR_PG | CTLR_PER | CTLR_MER | CTLR_OBPG | CTLR_OBER | CTLR_FTPG | CTLR_FTER | CTLR_BER32;
+
+// FLASH_STATR
+pub const STATR_BSY: u32 = 1 << 0;
+pub const STATR_WRPRTERR: u32 = 1 << 4;
+pub const STATR_EOP: u32 = 1 << 5;
+
+// RCC_CTLR
+pub const RCC_CTLR_HSION: u32 = 1 << 0;
+pub const RCC_CTLR_HSIRDY: u32 = 1 << 1;
+pub const RCC_CTLR_HSEON: u32 = 1 << 16;
+pub const RCC_CTLR_HSERDY: u32 = 1 << 17;
+pub const RCC_CTLR_PLLON: u32 = 1 << 24;
+pub const RCC_CTLR_PLLRDY: u32 = 1 << 25;
+
+// STK_CTLR
+pub const STK_CTLR_STE: u32 = 1 << 0;
+pub const STK_CTLR_STCLK: u32 = 1 << 2;
+
+/// Key register lock, opened by [`FLASH_KEY1`] followed by [`FLASH_KEY2`]
+#[derive(Copy, Clone, Debug)]
+struct Lock {
+    key1: bool,
+    /// A wrong key was written, the lock stays closed until the next reset
+    broken: bool,
+}
+
+impl Lock {
+    const RESET: Self = Self {
+        key1: false,
+        broken: false,
+    };
+
+    /// Feed `key` to the lock, `true` once it opens
+    fn write_key(&mut self, key: u32) -> bool {
+        if self.broken {
+            return false;
+        }
+        match (self.key1, key) {
+            (false, FLASH_KEY1) => {
+                self.key1 = true;
+                false
+            }
+            (true, FLASH_KEY2) => {
+                self.key1 = false;
+                true
+            }
+            _ => {
+                self.broken = true;
+                false
+            }
+        }
+    }
+}
+
+/// FLASH controller with the main flash and the option bytes behind it
+pub struct Flash {
+    /// Main flash from [`FLASH_BASE`] on, its size is reported as FLACAP
+    pub memory: Vec<u8>,
+    /// The eight option bytes and their complements
+    pub option_bytes: [u8; OB_SIZE as usize],
+    /// OBR.RDPRT
+    pub read_protected: bool,
+    /// OBR.SRAM_CODE_MODE
+    pub sram_code_mode: u8,
+    /// Raw WPR, a cleared bit protects the group
+    pub wpr: u32,
+    /// Reads of STATR that still show BSY after an operation started, `u32::MAX` never clears
+    pub busy_reads: u32,
+    /// CTLR mode bits and ADDR of every operation started with STRT
+    pub operations: Vec<(u32, u32)>,
+
+    pub actlr: u32,
+    pub ctlr: u32,
+    pub addr: u32,
+    statr: u32,
+    busy: u32,
+    lock: Lock,
+    fast_lock: Lock,
+    option_bytes_lock: Lock,
+    buffer: [u32; FAST_PAGE_SIZE as usize / 4],
+}
+
+impl Flash {
+    /// Erased flash of `capacity` bytes, locked and without any protection
+    pub fn new(capacity: u32) -> Self {
+        Self {
+            memory: vec![EMPTY; capacity as usize],
+            // RDPR unprotected, everything else erased
+            option_bytes: [
+                0xa5, 0x5a, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                0xff, 0xff,
+            ],
+            read_protected: false,
+            sram_code_mode: 0b11,
+            wpr: u32::MAX,
+            busy_reads: 0,
+            operations: Vec::new(),
+            actlr: 0,
+            ctlr: CTLR_LOCK | CTLR_FLOCK,
+            addr: 0,
+            statr: 0,
+            busy: 0,
+            lock: Lock::RESET,
+            fast_lock: Lock::RESET,
+            option_bytes_lock: Lock::RESET,
+            buffer: [u32::MAX; FAST_PAGE_SIZE as usize / 4],
+        }
+    }
+
+    /// FLACAP, the capacity in KB
+    pub fn capacity_kb(&self) -> u16 {
+        (self.memory.len() / 1024) as u16
+    }
+
+    pub fn read(&mut self, offset: u32) -> Option<u32> {
+        let value = match offset {
+            0x00 => self.actlr,
+            0x0C => {
+                let mut statr = self.statr;
+                if self.busy != 0 {
+                    statr |= STATR_BSY;
+                    if self.busy != u32::MAX {
+                        self.busy -= 1;
+                    }
+                }
+                statr
+            }
+            0x10 => self.ctlr,
+            0x14 => self.addr,
+            0x1C => (self.sram_code_mode as u32 & 0b11) << 8 | (self.read_protected as u32) << 1,
+            0x20 => self.wpr,
+            // KEYR, OBKEYR and MODEKEYR are write only
+            0x04 | 0x08 | 0x24 => 0,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    pub fn write(&mut self, offset: u32, value: u32) -> Option<()> {
+        match offset {
+            0x00 => self.actlr = value,
+            0x04 => {
+                if self.lock.write_key(value) {
+                    self.ctlr &= !CTLR_LOCK;
+                }
+            }
+            0x08 => {
+                if self.ctlr & CTLR_LOCK == 0 && self.option_bytes_lock.write_key(value) {
+                    self.ctlr |= CTLR_OBWRE;
+                }
+            }
+            0x0C => self.statr &= !(value & (STATR_EOP | STATR_WRPRTERR)),
+            0x10 => self.write_ctlr(value)?,
+            0x14 => self.addr = value,
+            0x24 => {
+                if self.ctlr & CTLR_LOCK == 0 && self.fast_lock.write_key(value) {
+                    self.ctlr &= !CTLR_FLOCK;
+                }
+            }
+            // OBR and WPR are read only
+            0x1C | 0x20 => (),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn write_ctlr(&mut self, value: u32) -> Option<()> {
+        // Both locks can be closed by software, but only opened with the keys
+        let lock = (self.ctlr | value) & (CTLR_LOCK | CTLR_FLOCK);
+        if self.ctlr & CTLR_LOCK != 0 {
+            self.ctlr |= lock;
+            return Some(());
+        }
+        let mut modes = value & CTLR_MODES;
+        if lock & CTLR_FLOCK != 0 {
+            modes &= !(CTLR_FTPG | CTLR_FTER | CTLR_BER32);
+        }
+        // OBWRE is only set by the keys, writing 0 clears it
+        let obwre = self.ctlr & value & CTLR_OBWRE;
+        if obwre == 0 {
+            modes &= !(CTLR_OBPG | CTLR_OBER);
+        }
+        if lock & CTLR_LOCK != 0 {
+            modes = 0;
+            self.lock = Lock::RESET;
+            self.fast_lock = Lock::RESET;
+            self.option_bytes_lock = Lock::RESET;
+        }
+        self.ctlr = lock | obwre | modes;
+
+        let actions = value & (CTLR_STRT | CTLR_BUFRST | CTLR_BUFLOAD);
+        if actions != 0 && self.busy != 0 {
+            // The controller ignores new operations while busy
+            return None;
+        }
+        if value & CTLR_BUFRST != 0 && modes == CTLR_FTPG {
+            self.buffer.fill(u32::MAX);
+            self.operate();
+        }
+        if value & CTLR_BUFLOAD != 0 && modes == CTLR_FTPG {
+            self.operate();
+        }
+        if value & CTLR_STRT != 0 {
+            self.start(modes)?;
+        }
+        Some(())
+    }
+
+    /// Let the operation take [`Self::busy_reads`], then report its end
+    fn operate(&mut self) {
+        self.busy = self.busy_reads;
+        self.statr |= STATR_EOP;
+    }
+
+    fn start(&mut self, modes: u32) -> Option<()> {
+        self.operations.push((modes, self.addr));
+        match modes {
+            CTLR_PER => self.erase_sector(0x1000),
+            CTLR_FTER => self.erase_sector(0x100),
+            CTLR_BER32 => self.erase_sector(0x8000),
+            CTLR_MER => {
+                if self.wpr != u32::MAX {
+                    self.statr |= STATR_WRPRTERR;
+                } else {
+                    self.memory.fill(EMPTY);
+                }
+            }
+            CTLR_OBER => {
+                // Unprotecting a protected chip takes the whole flash with it
+                if self.read_protected {
+                    self.memory.fill(EMPTY);
+                }
+                self.option_bytes.fill(EMPTY);
+            }
+            CTLR_FTPG => {
+                let offset = self.offset(self.addr & !(FAST_PAGE_SIZE - 1))?;
+                if self.protected(offset) {
+                    self.statr |= STATR_WRPRTERR;
+                } else {
+                    for (index, word) in self.buffer.iter().enumerate() {
+                        for (byte, value) in word.to_le_bytes().iter().enumerate() {
+                            self.memory[offset + index * 4 + byte] &= value;
+                        }
+                    }
+                }
+            }
+            _ => return None,
+        }
+        self.operate();
+        Some(())
+    }
+
+    fn erase_sector(&mut self, len: u32) {
+        let Some(offset) = self.offset(self.addr & !(len - 1)) else {
+            return;
+        };
+        let range = offset..(offset + len as usize).min(self.memory.len());
+        if range.clone().any(|offset| self.protected(offset)) {
+            self.statr |= STATR_WRPRTERR;
+            return;
+        }
+        self.memory[range].fill(EMPTY);
+    }
+
+    /// Offset of `address` into [`Self::memory`], if it's in flash
+    pub fn offset(&self, address: u32) -> Option<usize> {
+        let offset = address.checked_sub(FLASH_BASE)? as usize;
+        (offset < self.memory.len()).then_some(offset)
+    }
+
+    fn protected(&self, offset: usize) -> bool {
+        let group = (offset as u32 / WRP_GROUP_SIZE).min(31);
+        self.wpr & (1 << group) == 0
+    }
+
+    /// Write to main flash or the option bytes, which programs them in the right mode
+    pub fn write_memory(&mut self, address: u32, size: u32, value: u32) -> Option<()> {
+        if self.busy != 0 {
+            return None;
+        }
+        match (self.ctlr & CTLR_MODES, size) {
+            (CTLR_PG, 2) if address & 1 == 0 => {
+                let offset = self.offset(address)?;
+                if self.protected(offset) {
+                    self.statr |= STATR_WRPRTERR;
+                } else {
+                    self.memory[offset] &= value as u8;
+                    self.memory[offset + 1] &= (value >> 8) as u8;
+                }
+                self.operate();
+            }
+            (CTLR_OBPG, 2) if address & 1 == 0 => {
+                let offset = address.checked_sub(OB_BASE).filter(|&o| o < OB_SIZE)? as usize;
+                self.option_bytes[offset] &= value as u8;
+                self.option_bytes[offset + 1] &= (value >> 8) as u8;
+                self.operate();
+            }
+            (CTLR_FTPG, 4) if address & 3 == 0 => {
+                self.offset(address)?;
+                self.buffer[(address % FAST_PAGE_SIZE) as usize / 4] = value;
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+/// RCC, where oscillators and the PLL get ready as soon as they are switched on
+pub struct Rcc {
+    pub ctlr: u32,
+    pub cfgr0: u32,
+    /// RSTSCKR, LSION stays as written when the independent watchdog starts
+    pub rstsckr: u32,
+    /// Every other register, as written
+    registers: [u32; 0x100],
+}
+
+impl Rcc {
+    pub fn new() -> Self {
+        Self {
+            ctlr: RCC_CTLR_HSION,
+            cfgr0: 0,
+            rstsckr: 0,
+            registers: [0; 0x100],
+        }
+    }
+
+    pub fn read(&mut self, offset: u32) -> Option<u32> {
+        let value = match offset {
+            0x00 => {
+                let ready = (self.ctlr & RCC_CTLR_HSION) << 1
+                    | (self.ctlr & RCC_CTLR_HSEON) << 1
+                    | (self.ctlr & RCC_CTLR_PLLON) << 1;
+                let ready_bits = RCC_CTLR_HSIRDY | RCC_CTLR_HSERDY | RCC_CTLR_PLLRDY;
+                self.ctlr & !ready_bits | ready
+            }
+            // SWS follows SW
+            0x04 => self.cfgr0 & !0b1100 | (self.cfgr0 & 0b11) << 2,
+            0x24 => self.rstsckr,
+            _ => self.registers[offset as usize / 4],
+        };
+        Some(value)
+    }
+
+    pub fn write(&mut self, offset: u32, value: u32) -> Option<()> {
+        match offset {
+            0x00 => self.ctlr = value,
+            0x04 => self.cfgr0 = value & !0b1100,
+            0x24 => self.rstsckr = value,
+            _ => self.registers[offset as usize / 4] = value,
+        }
+        Some(())
+    }
+}
+
+impl Default for Rcc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// SysTick of the QingKe core, counting up while STE is set
+pub struct SysTick {
+    pub ctlr: u32,
+    pub count: u64,
+    /// HCLK cycles per executed instruction
+    pub cycles_per_instruction: u64,
+}
+
+impl SysTick {
+    pub fn new() -> Self {
+        Self {
+            ctlr: 0,
+            count: 0,
+            cycles_per_instruction: 1,
+        }
+    }
+
+    /// Advance by one instruction
+    pub fn tick(&mut self) {
+        if self.ctlr & STK_CTLR_STE == 0 {
+            return;
+        }
+        let ticks = if self.ctlr & STK_CTLR_STCLK != 0 {
+            self.cycles_per_instruction
+        } else {
+            self.cycles_per_instruction / 8
+        };
+        self.count = self.count.wrapping_add(ticks);
+    }
+
+    pub fn read(&mut self, offset: u32) -> Option<u32> {
+        let value = match offset {
+            0x00 => self.ctlr,
+            0x08 => self.count as u32,
+            0x0C => (self.count >> 32) as u32,
+            0x04 | 0x10 | 0x14 => 0,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    pub fn write(&mut self, offset: u32, value: u32) -> Option<()> {
+        match offset {
+            0x00 => self.ctlr = value,
+            0x08 => self.count = self.count & !0xffff_ffff | value as u64,
+            0x0C => self.count = self.count & 0xffff_ffff | (value as u64) << 32,
+            0x04 | 0x10 | 0x14 => (),
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+impl Default for SysTick {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Independent and window watchdog, only counting how often they were reloaded
+#[derive(Default)]
+pub struct Watchdogs {
+    /// The start key 0xCCCC was written to IWDG_CTLR, which leaves RSTSCKR.LSION alone
+    pub iwdg_running: bool,
+    /// Writes of the reload key 0xAAAA to IWDG_CTLR while it runs
+    pub iwdg_reloads: u32,
+    pub wwdg_ctlr: u32,
+    pub wwdg_cfgr: u32,
+    /// Writes to WWDG_CTLR
+    pub wwdg_refreshes: u32,
+}
+
+impl Watchdogs {
+    pub fn read_iwdg(&mut self, offset: u32) -> Option<u32> {
+        (offset < 0x10).then_some(0)
+    }
+
+    pub fn write_iwdg(&mut self, offset: u32, value: u32) -> Option<()> {
+        if offset == 0 {
+            match value & 0xffff {
+                0xCCCC => self.iwdg_running = true,
+                0xAAAA if self.iwdg_running => self.iwdg_reloads += 1,
+                _ => (),
+            }
+        }
+        (offset < 0x10).then_some(())
+    }
+
+    pub fn read_wwdg(&mut self, offset: u32) -> Option<u32> {
+        match offset {
+            0x00 => Some(self.wwdg_ctlr),
+            0x04 => Some(self.wwdg_cfgr),
+            0x08 => Some(0),
+            _ => None,
+        }
+    }
+
+    pub fn write_wwdg(&mut self, offset: u32, value: u32) -> Option<()> {
+        match offset {
+            0x00 => {
+                self.wwdg_refreshes += 1;
+                self.wwdg_ctlr = value;
+            }
+            0x04 => self.wwdg_cfgr = value,
+            0x08 => (),
+            _ => return None,
+        }
+        Some(())
+    }
+}
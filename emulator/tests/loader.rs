@@ -0,0 +1,229 @@
+//! Runs the flash loader built by the main crate on the emulator
+//!
+//! Build it first with `cargo build --release` in the repository root, or point
+//! `CH32V307_FLASHLOADER` at the binary. A loader in the root's `target` that is older than the
+//! sources it's built from is refused, rather than testing the leftovers of an earlier build.
+
+use ch32v307_flashloader_emulator::mmio::{CTLR_LOCK, FLASH_BASE, STK_CTLR_STE};
+use ch32v307_flashloader_emulator::Emulator;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// The repository root with the sources of the loader
+const ROOT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/..");
+
+/// SRAM of the CH32V307
+const RAM_BASE: u32 = 0x2000_0000;
+const RAM_SIZE: u32 = 0x1_0000;
+
+/// Error codes returned by the loader
+const OK: u32 = 0;
+const WRITE_PROTECTED: u32 = 2;
+const TIME_OUT: u32 = 4;
+const MISALIGNED: u32 = 5;
+const OUT_OF_RANGE: u32 = 6;
+
+fn loader() -> Vec<u8> {
+    let path = match env::var("CH32V307_FLASHLOADER") {
+        Ok(path) => path,
+        Err(_) => {
+            let path =
+                format!("{ROOT}/target/riscv32imac-unknown-none-elf/release/ch32v307-flashloader");
+            check_up_to_date(Path::new(&path));
+            path
+        }
+    };
+    fs::read(&path).unwrap_or_else(|error| {
+        panic!("can't read the flash loader at {path}: {error}, run `cargo build --release` first")
+    })
+}
+
+fn modified(path: &Path) -> SystemTime {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .unwrap_or_else(|error| panic!("can't stat {}: {error}", path.display()))
+}
+
+/// Fail if any of the sources changed after the loader at `path` was built
+fn check_up_to_date(path: &Path) {
+    // A missing loader is reported when reading it
+    if !path.exists() {
+        return;
+    }
+    let built = modified(path);
+
+    let mut sources = vec![
+        PathBuf::from(format!("{ROOT}/Cargo.toml")),
+        PathBuf::from(format!("{ROOT}/build.rs")),
+        PathBuf::from(format!("{ROOT}/loader.x")),
+    ];
+    let mut dirs = vec![PathBuf::from(format!("{ROOT}/src"))];
+    while let Some(dir) = dirs.pop() {
+        let entries = fs::read_dir(&dir)
+            .unwrap_or_else(|error| panic!("can't list {}: {error}", dir.display()));
+        for entry in entries {
+            let entry =
+                entry.unwrap_or_else(|error| panic!("can't list {}: {error}", dir.display()));
+            if entry.path().is_dir() {
+                dirs.push(entry.path());
+            } else {
+                sources.push(entry.path());
+            }
+        }
+    }
+
+    for source in sources {
+        assert!(
+            modified(&source) <= built,
+            "the flash loader at {} is older than {}, run `cargo build --release` first",
+            path.display(),
+            source.display()
+        );
+    }
+}
+
+/// The loader at `ram_base`, after a successful Init
+fn emulator(ram_base: u32) -> Emulator {
+    let mut emulator = Emulator::new(&loader(), ram_base, RAM_SIZE).unwrap();
+    assert_eq!(emulator.call("Init", &[FLASH_BASE, 0, 2]), Ok(OK));
+    emulator
+}
+
+/// A page worth of data with all kinds of bit patterns
+fn page() -> Vec<u8> {
+    (0..=255).collect()
+}
+
+#[test]
+fn erase_and_program() {
+    let mut emulator = emulator(RAM_BASE);
+    let data = page();
+    emulator.bus.flash.memory[..0x1000].fill(0);
+
+    assert_eq!(emulator.call("EraseSector", &[FLASH_BASE]), Ok(OK));
+    assert!(emulator.bus.flash.memory[..0x100]
+        .iter()
+        .all(|&b| b == 0xff));
+
+    let buffer = emulator.write_buffer(&data);
+    let size = data.len() as u32;
+    assert_eq!(
+        emulator.call("ProgramPage", &[FLASH_BASE, size, buffer]),
+        Ok(OK)
+    );
+    assert_eq!(emulator.bus.flash.memory[..data.len()], data[..]);
+    assert_eq!(
+        emulator.call("Verify", &[FLASH_BASE, size, buffer]),
+        Ok(FLASH_BASE + size)
+    );
+}
+
+#[test]
+fn unaligned_buffers_and_sizes() {
+    let mut emulator = emulator(RAM_BASE);
+    let data = page();
+
+    // Start the data one byte into the buffer and leave out the last byte
+    let buffer = emulator.write_buffer(&data) + 1;
+    assert_eq!(
+        emulator.call("ProgramPage", &[FLASH_BASE, 254, buffer]),
+        Ok(OK)
+    );
+    assert_eq!(emulator.bus.flash.memory[..254], data[1..255]);
+    assert!(emulator.bus.flash.memory[254..].iter().all(|&b| b == 0xff));
+
+    assert_eq!(
+        emulator.call("ProgramPage", &[FLASH_BASE + 0x1001, 2, buffer]),
+        Ok(MISALIGNED)
+    );
+}
+
+#[test]
+fn uninit_restores_the_chip() {
+    let mut emulator = Emulator::new(&loader(), RAM_BASE, RAM_SIZE).unwrap();
+    let rcc_cfgr0 = emulator.bus.rcc.cfgr0;
+    let extend_ctr = emulator.bus.extend_ctr;
+
+    assert_eq!(emulator.call("Init", &[FLASH_BASE, 0, 2]), Ok(OK));
+    assert_eq!(
+        emulator.bus.rcc.cfgr0 & 0b11,
+        0b10,
+        "not running from the PLL"
+    );
+    assert_eq!(emulator.bus.flash.ctlr & CTLR_LOCK, 0);
+
+    assert_eq!(emulator.call("UnInit", &[2]), Ok(OK));
+    assert_eq!(emulator.bus.rcc.cfgr0, rcc_cfgr0);
+    assert_eq!(emulator.bus.extend_ctr, extend_ctr);
+    assert_ne!(emulator.bus.flash.ctlr & CTLR_LOCK, 0);
+    assert_eq!(emulator.bus.systick.ctlr & STK_CTLR_STE, 0);
+}
+
+#[test]
+fn runs_from_anywhere_in_ram() {
+    for ram_base in [0x2000_0000, 0x2000_1000, 0x2000_8004] {
+        let mut emulator = emulator(ram_base);
+        let data = page();
+
+        assert_eq!(emulator.call("EraseSector", &[FLASH_BASE + 0x1000]), Ok(OK));
+        let buffer = emulator.write_buffer(&data);
+        assert_eq!(
+            emulator.call("ProgramPage", &[FLASH_BASE + 0x1000, 256, buffer]),
+            Ok(OK),
+            "loaded at {ram_base:#010x}"
+        );
+        assert_eq!(emulator.bus.flash.memory[0x1000..0x1100], data[..]);
+    }
+}
+
+#[test]
+fn errors_are_recorded() {
+    let mut emulator = emulator(RAM_BASE);
+    let address = FLASH_BASE + 0x0010_0000;
+
+    assert_eq!(emulator.call("EraseSector", &[address]), Ok(OUT_OF_RANGE));
+
+    let last_error = emulator.call("GetLastError", &[]).unwrap();
+    let record = emulator.bus.read_bytes(last_error, 8).unwrap();
+    assert_eq!(record[..4], OUT_OF_RANGE.to_le_bytes());
+    assert_eq!(record[4..], address.to_le_bytes());
+}
+
+#[test]
+fn write_protected_flash_is_left_alone() {
+    let mut emulator = emulator(RAM_BASE);
+    emulator.bus.flash.wpr = !1;
+    emulator.bus.flash.memory[0] = 0;
+
+    assert_eq!(
+        emulator.call("EraseSector", &[FLASH_BASE]),
+        Ok(WRITE_PROTECTED)
+    );
+    assert_eq!(emulator.bus.flash.memory[0], 0);
+    assert!(emulator.bus.flash.operations.is_empty());
+}
+
+#[test]
+fn busy_controller_times_out() {
+    let mut emulator = emulator(RAM_BASE);
+    emulator.bus.flash.busy_reads = u32::MAX;
+    // Let the 6 s erase timeout pass in a few thousand instructions
+    emulator.bus.systick.cycles_per_instruction = 100_000;
+
+    assert_eq!(emulator.call("EraseSector", &[FLASH_BASE]), Ok(TIME_OUT));
+}
+
+#[test]
+fn watchdog_is_reloaded() {
+    let mut emulator = Emulator::new(&loader(), RAM_BASE, RAM_SIZE).unwrap();
+    // Started by the application, which leaves RSTSCKR.LSION clear
+    emulator.bus.watchdogs.iwdg_running = true;
+    emulator.bus.flash.busy_reads = 100;
+
+    assert_eq!(emulator.call("Init", &[FLASH_BASE, 0, 1]), Ok(OK));
+    assert_eq!(emulator.call("EraseSector", &[FLASH_BASE]), Ok(OK));
+
+    assert!(emulator.bus.watchdogs.iwdg_reloads >= 100);
+}
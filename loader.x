@@ -16,6 +16,10 @@ SECTIONS {
         KEEP(*(.text))
         KEEP(*(.text.*))
 
+        /* The functions generated by flash-algorithm */
+        KEEP(*(.entry))
+        KEEP(*(.entry.*))
+
         KEEP(*(.rodata))
         KEEP(*(.rodata.*))
 
@@ -0,0 +1,1073 @@
+//! The flash algorithm of the main flash, independent of the hardware it talks to
+//!
+//! The loader binaries keep one [`Algorithm`] in a static and forward the CMSIS functions to it.
+
+use crate::chip::CHIP;
+use crate::controller::{ERASE_TIME_OUT, PROGRAM_TIME_OUT};
+use crate::device::{dev_name, uniform_sectors, FlashDeviceDescription, ONCHIP, VERSION};
+use crate::error::{Error, LastError};
+use crate::hal::{ClockController, FlashController, Mode};
+
+/// Start of the main flash
+pub const FLASH_BASE: u32 = 0x0800_0000;
+
+/// Value of an erased flash byte
+pub const EMPTY: u8 = 0xff;
+
+/// Size of the page buffer used by fast programming
+pub const FAST_PAGE_SIZE: usize = 256;
+
+/// Time in ms a mass erase may take before `EraseChip` gives up
+pub const CHIP_ERASE_TIME_OUT: u32 = 20_000;
+
+/// Flash covered by each of WRPR bits 0 to 30, bit 31 covers everything above
+pub const WRP_GROUP_SIZE: u32 = 0x1000;
+
+#[cfg(all(feature = "erase-256", feature = "erase-32k"))]
+compile_error!("features `erase-256` and `erase-32k` are mutually exclusive");
+
+/// Fast page erase
+#[cfg(feature = "erase-256")]
+pub const ERASE_MODE: Mode = Mode::Erase256;
+/// Bytes erased by a single `EraseSector` call
+#[cfg(feature = "erase-256")]
+pub const ERASE_SIZE: u32 = 0x100;
+
+/// 32 KB block erase
+#[cfg(all(feature = "erase-32k", not(feature = "erase-256")))]
+pub const ERASE_MODE: Mode = Mode::Erase32k;
+/// Bytes erased by a single `EraseSector` call
+#[cfg(all(feature = "erase-32k", not(feature = "erase-256")))]
+pub const ERASE_SIZE: u32 = 0x8000;
+
+/// Standard page erase
+#[cfg(not(any(feature = "erase-256", feature = "erase-32k")))]
+pub const ERASE_MODE: Mode = Mode::PageErase;
+/// Bytes erased by a single `EraseSector` call
+#[cfg(not(any(feature = "erase-256", feature = "erase-32k")))]
+pub const ERASE_SIZE: u32 = CHIP.page_size;
+
+#[cfg(all(
+    feature = "gd32vf103",
+    any(feature = "fast-program", feature = "erase-256", feature = "erase-32k")
+))]
+compile_error!("the GD32VF103 has no fast programming or erase modes, `fast-program`, `erase-256` and `erase-32k` only apply to the CH32");
+
+#[cfg(any(
+    all(feature = "code-192k", feature = "code-224k"),
+    all(feature = "code-192k", feature = "code-288k"),
+    all(feature = "code-192k", feature = "flash-480k"),
+    all(feature = "code-224k", feature = "code-288k"),
+    all(feature = "code-224k", feature = "flash-480k"),
+    all(feature = "code-288k", feature = "flash-480k"),
+))]
+compile_error!("only one of the features `code-192k`, `code-224k`, `code-288k` and `flash-480k` may be enabled");
+
+#[cfg(all(
+    any(feature = "ch32v203", feature = "ch32v208", feature = "gd32vf103"),
+    any(
+        feature = "code-192k",
+        feature = "code-224k",
+        feature = "code-288k",
+        feature = "flash-480k"
+    )
+))]
+compile_error!("the features `code-192k`, `code-224k`, `code-288k` and `flash-480k` only apply to the CH32V30x");
+
+// Flash described by `FlashDevice`, the name follows the part name from `CHIP`. `init` limits
+// it further to what the part and its SRAM_CODE_MODE actually provide.
+
+#[cfg(feature = "code-192k")]
+pub const DEVICE_SIZE: u32 = 192 * 1024;
+#[cfg(feature = "code-192k")]
+pub const DEVICE_NAME: &str = "192 KB zero-wait flash";
+
+#[cfg(feature = "code-224k")]
+pub const DEVICE_SIZE: u32 = 224 * 1024;
+#[cfg(feature = "code-224k")]
+pub const DEVICE_NAME: &str = "224 KB zero-wait flash";
+
+#[cfg(feature = "code-288k")]
+pub const DEVICE_SIZE: u32 = 288 * 1024;
+#[cfg(feature = "code-288k")]
+pub const DEVICE_NAME: &str = "288 KB zero-wait flash";
+
+#[cfg(feature = "flash-480k")]
+pub const DEVICE_SIZE: u32 = 480 * 1024;
+#[cfg(feature = "flash-480k")]
+pub const DEVICE_NAME: &str = "480 KB internal flash";
+
+#[cfg(not(any(
+    feature = "code-192k",
+    feature = "code-224k",
+    feature = "code-288k",
+    feature = "flash-480k"
+)))]
+pub const DEVICE_SIZE: u32 = CHIP.flash_size;
+#[cfg(not(any(
+    feature = "code-192k",
+    feature = "code-224k",
+    feature = "code-288k",
+    feature = "flash-480k",
+    feature = "ch32v203",
+    feature = "ch32v208",
+    feature = "gd32vf103"
+)))]
+pub const DEVICE_NAME: &str = "256 KB internal flash";
+#[cfg(any(feature = "ch32v203", feature = "ch32v208", feature = "gd32vf103"))]
+pub const DEVICE_NAME: &str = "128 KB internal flash";
+
+// The sector table in `FlashDevice` has to cover the device with whole sectors, all sector
+// sizes are powers of two
+const _: () = assert!(DEVICE_SIZE & (ERASE_SIZE - 1) == 0);
+
+/// Data handed to one `ProgramPage` call, `FlashDevice.page_size`
+pub const PAGE_SIZE: u32 = if cfg!(feature = "fast-program") {
+    FAST_PAGE_SIZE as u32
+} else {
+    1024
+};
+
+/// `FlashDevice.dev_name`
+pub const DEV_NAME: [u8; 128] = dev_name(&[CHIP.name, " ", DEVICE_NAME]);
+
+/// `FlashDevice` of the main flash loader
+pub const FLASH_DEVICE: FlashDeviceDescription = FlashDeviceDescription {
+    vers: VERSION,
+    dev_name: DEV_NAME,
+    dev_type: ONCHIP,
+    dev_addr: FLASH_BASE,
+    device_size: DEVICE_SIZE,
+    page_size: PAGE_SIZE,
+    _reserved: 0,
+    empty: EMPTY,
+    program_time_out: PROGRAM_TIME_OUT,
+    erase_time_out: ERASE_TIME_OUT,
+    flash_sectors: uniform_sectors(ERASE_SIZE),
+};
+
+/// State shared by the calls of one flash algorithm
+pub struct Algorithm<F, C> {
+    pub flash: F,
+    pub clock: C,
+    /// Size of the flash in bytes, as detected by [`Algorithm::init`]
+    size: u32,
+    last_error: LastError,
+}
+
+impl<F, C> Algorithm<F, C> {
+    pub const fn new(flash: F, clock: C) -> Self {
+        Self {
+            flash,
+            clock,
+            size: 0,
+            last_error: LastError::NONE,
+        }
+    }
+
+    /// Details on the last failure, for `GetLastError`
+    pub fn last_error(&self) -> &LastError {
+        &self.last_error
+    }
+}
+
+impl<F: FlashController, C: ClockController> Algorithm<F, C> {
+    /// Record `error`, raised while working on `address`, as the last error
+    pub(crate) fn fail(&mut self, error: Error, address: u32) -> Error {
+        let statr = self.flash.status();
+        self.fail_with_statr(error, address, statr)
+    }
+
+    /// Like [`Self::fail`], for a STATR value read before its flags were cleared
+    pub(crate) fn fail_with_statr(&mut self, error: Error, address: u32, statr: u32) -> Error {
+        self.last_error = LastError {
+            code: error as i32,
+            address,
+            statr,
+        };
+        error
+    }
+
+    /// Forget about earlier errors, set up the clocks and then run `setup`, every `Init`
+    ///
+    /// CMSIS hosts don't call `UnInit` after a failed `Init`, so on failure the clocks are
+    /// handed back right away.
+    pub(crate) fn init_with(
+        &mut self,
+        setup: impl FnOnce(&mut Self) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        self.last_error = LastError::NONE;
+        let result = match self.clock.init() {
+            Ok(()) => setup(self),
+            Err(error) => Err(self.fail(error, 0)),
+        };
+        // A failed clock setup has saved the state as well. The first error is the one to report.
+        if result.is_err() && self.clock.initialized() {
+            let _ = self.clock.uninit();
+        }
+        result
+    }
+
+    /// Set up clocks and controller and find out how much flash there is
+    pub fn init(&mut self) -> Result<(), Error> {
+        self.init_with(Self::init_flash)
+    }
+
+    /// The part of [`Self::init`] after the clock setup
+    fn init_flash(&mut self) -> Result<(), Error> {
+        // Usable flash differs between parts, the electronic signature has the real capacity
+        let capacity = self.flash.capacity();
+        // The USER option byte splits the zero-wait area between code flash and SRAM
+        let code_size = CHIP.code_sizes[(self.flash.sram_code_mode() & 0b11) as usize];
+        let size = if cfg!(feature = "flash-480k") {
+            // Also program the flash behind the zero-wait area
+            capacity
+        } else {
+            capacity.min(code_size)
+        };
+        self.size = size.min(DEVICE_SIZE);
+
+        self.unlock()?;
+        if cfg!(any(
+            feature = "fast-program",
+            feature = "erase-256",
+            feature = "erase-32k"
+        )) {
+            self.unlock_fast()?;
+        }
+
+        Ok(())
+    }
+
+    /// Hand the chip back the way [`Self::init`] found it
+    pub fn uninit(&mut self) -> Result<(), Error> {
+        self.clock.uninit().map_err(|error| self.fail(error, 0))
+    }
+
+    /// Fail unless `init` was called, `adr` is only used to report the error
+    fn check_initialized(&mut self, adr: u32) -> Result<(), Error> {
+        if !self.clock.initialized() {
+            return Err(self.fail(Error::BadCallOrder, adr));
+        }
+        Ok(())
+    }
+
+    /// Fail unless `len` bytes starting at `adr` lie within the detected flash
+    fn check_in_flash(&mut self, adr: u32, len: u32) -> Result<(), Error> {
+        let in_flash = match adr.checked_sub(FLASH_BASE) {
+            Some(offset) => offset <= self.size && len <= self.size - offset,
+            None => false,
+        };
+        if !in_flash {
+            return Err(self.fail(Error::OutOfRange, adr));
+        }
+        Ok(())
+    }
+
+    /// Fail if any of the `len` bytes at `adr` is write protected, `adr` has to be in flash
+    fn check_write_protection(&mut self, adr: u32, len: u32) -> Result<(), Error> {
+        let locked = self.flash.write_protected_groups();
+        let offset = adr - FLASH_BASE;
+        let first = (offset / WRP_GROUP_SIZE).min(31);
+        let last = ((offset + len.max(1) - 1) / WRP_GROUP_SIZE).min(31);
+
+        if (first..=last).any(|group| locked & (1 << group) != 0) {
+            return Err(self.fail(Error::WriteProtected, adr));
+        }
+        Ok(())
+    }
+
+    /// Erase the [`ERASE_SIZE`] bytes at `adr`
+    pub fn erase_sector(&mut self, adr: u32) -> Result<(), Error> {
+        self.check_initialized(adr)?;
+        self.check_in_flash(adr, ERASE_SIZE)?;
+        self.check_write_protection(adr, ERASE_SIZE)?;
+
+        self.run(ERASE_MODE, adr, ERASE_TIME_OUT)
+    }
+
+    /// Erase the complete flash with a mass erase
+    pub fn erase_chip(&mut self) -> Result<(), Error> {
+        self.check_initialized(FLASH_BASE)?;
+
+        self.run(Mode::MassErase, FLASH_BASE, CHIP_ERASE_TIME_OUT)
+    }
+
+    /// Program `data` into flash at `adr`
+    ///
+    /// `data` may have any length and alignment. Whatever is left of the last half-word or page
+    /// is padded with [`EMPTY`], which leaves the flash behind `data` as it was.
+    pub fn program_page(&mut self, adr: u32, data: &[u8]) -> Result<(), Error> {
+        self.check_initialized(adr)?;
+        self.check_in_flash(adr, data.len() as u32)?;
+        self.check_write_protection(adr, data.len() as u32)?;
+
+        if cfg!(feature = "fast-program") {
+            self.program_fast(adr, data)
+        } else {
+            self.program_half_words(adr, data)
+        }
+    }
+
+    /// Standard programming, one half-word at a time, `adr` has to be even
+    fn program_half_words(&mut self, adr: u32, data: &[u8]) -> Result<(), Error> {
+        if adr & 1 != 0 {
+            return Err(self.fail(Error::Misaligned, adr));
+        }
+
+        self.flash.set_mode(Mode::Program, true);
+        for (offset, chunk) in data.chunks(2).enumerate() {
+            let dst_adr = adr + offset as u32 * 2;
+            let mut bytes = [EMPTY; 2];
+            for (byte, value) in bytes.iter_mut().zip(chunk) {
+                *byte = *value;
+            }
+            let half_word = u16::from_le_bytes(bytes);
+
+            self.flash.write_u16(dst_adr, half_word);
+            let mut result = self.wait_for_flash(dst_adr, PROGRAM_TIME_OUT);
+            if result.is_ok() {
+                result = self.check_programmed(dst_adr, chunk);
+            }
+            if result.is_err() {
+                self.flash.set_mode(Mode::Program, false);
+                return result;
+            }
+        }
+        self.flash.set_mode(Mode::Program, false);
+
+        Ok(())
+    }
+
+    /// Fast programming, one [`FAST_PAGE_SIZE`] page at a time, `adr` has to start a page
+    fn program_fast(&mut self, adr: u32, data: &[u8]) -> Result<(), Error> {
+        if adr & (FAST_PAGE_SIZE as u32 - 1) != 0 {
+            return Err(self.fail(Error::Misaligned, adr));
+        }
+
+        for (offset, page) in data.chunks(FAST_PAGE_SIZE).enumerate() {
+            self.program_fast_page(adr + (offset * FAST_PAGE_SIZE) as u32, page)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fast programming of the page at `adr`, `data` is padded to a whole page
+    ///
+    /// The page buffer takes four words at a time. Once it's full, a single operation programs
+    /// it into the page, which has to be erased.
+    fn program_fast_page(&mut self, adr: u32, data: &[u8]) -> Result<(), Error> {
+        // Reset the page buffer
+        self.flash.set_mode(Mode::FastProgram, true);
+        self.flash.reset_buffer();
+        let result = self.wait_for_flash(adr, PROGRAM_TIME_OUT);
+        self.flash.set_mode(Mode::FastProgram, false);
+        result?;
+
+        // Load the buffer, four words at a time
+        for block in 0..FAST_PAGE_SIZE / 16 {
+            let dst_adr = adr + block as u32 * 16;
+
+            self.flash.set_mode(Mode::FastProgram, true);
+            for word in 0..4 {
+                let index = block * 16 + word * 4;
+                // Byte by byte, the buffer handed to ProgramPage needn't be word aligned
+                let mut bytes = [EMPTY; 4];
+                for (byte, value) in bytes.iter_mut().zip(data.iter().skip(index)) {
+                    *byte = *value;
+                }
+                self.flash
+                    .write_u32(dst_adr + word as u32 * 4, u32::from_le_bytes(bytes));
+            }
+            self.flash.load_buffer();
+            let result = self.wait_for_flash(dst_adr, PROGRAM_TIME_OUT);
+            self.flash.set_mode(Mode::FastProgram, false);
+            result?;
+        }
+
+        // Program the buffer into the page
+        self.run(Mode::FastProgram, adr, PROGRAM_TIME_OUT)?;
+
+        // Make sure the page holds what was loaded into the buffer
+        self.check_programmed(adr, data)
+    }
+
+    /// Fail unless the flash at `adr` reads back as `data`, the padding isn't checked
+    fn check_programmed(&mut self, adr: u32, data: &[u8]) -> Result<(), Error> {
+        for (offset, byte) in data.iter().enumerate() {
+            let dst_adr = adr + offset as u32;
+            if self.flash.read_u8(dst_adr) != *byte {
+                return Err(self.fail(Error::Program, dst_adr));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check if `sz` bytes of flash starting at `adr` are erased
+    ///
+    /// The address of the first byte that isn't is recorded with the error.
+    pub fn blank_check(&mut self, adr: u32, sz: u32) -> Result<(), Error> {
+        self.check_initialized(adr)?;
+        self.check_in_flash(adr, sz)?;
+
+        let empty_word = u32::from_ne_bytes([EMPTY; 4]);
+        let mut adr = adr;
+        let end = adr + sz;
+
+        // Bytes up to the first word boundary
+        while adr < end && adr & 3 != 0 {
+            if self.flash.read_u8(adr) != EMPTY {
+                return Err(self.fail(Error::VerifyMismatch, adr));
+            }
+            adr += 1;
+        }
+        // Whole words, up to the first one that isn't blank
+        while end - adr >= 4 && self.flash.read_u32(adr) == empty_word {
+            adr += 4;
+        }
+        // Bytes after the last word boundary, or of the word that isn't blank
+        while adr < end {
+            if self.flash.read_u8(adr) != EMPTY {
+                return Err(self.fail(Error::VerifyMismatch, adr));
+            }
+            adr += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Compare flash at `adr` with `expected`
+    ///
+    /// `Return` - the address after the range if the contents match, otherwise the address of
+    /// the first mismatch, which is also recorded as [`Error::VerifyMismatch`].
+    pub fn verify(&mut self, adr: u32, expected: &[u8]) -> u32 {
+        for (offset, byte) in expected.iter().enumerate() {
+            let address = adr + offset as u32;
+            if self.flash.read_u8(address) != *byte {
+                self.fail(Error::VerifyMismatch, address);
+                return address;
+            }
+        }
+
+        adr + expected.len() as u32
+    }
+
+    /// Copy the flash at `adr` into `data`, for tools that read flash through the loader
+    pub fn read(&mut self, adr: u32, data: &mut [u8]) -> Result<(), Error> {
+        self.check_initialized(adr)?;
+        self.check_in_flash(adr, data.len() as u32)?;
+
+        for (offset, byte) in data.iter_mut().enumerate() {
+            *byte = self.flash.read_u8(adr + offset as u32);
+        }
+
+        Ok(())
+    }
+
+    /// Bitmap with a 1 for each write protected group of [`WRP_GROUP_SIZE`] bytes
+    pub fn write_protected_groups(&self) -> u32 {
+        self.flash.write_protected_groups()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Access, SimClock, SimFlash};
+
+    /// Initialized algorithm on erased, unprotected flash
+    fn algorithm() -> Algorithm<SimFlash, SimClock> {
+        let mut algorithm = Algorithm::new(SimFlash::new(DEVICE_SIZE), SimClock::new());
+        algorithm.init().unwrap();
+        algorithm
+    }
+
+    /// A page worth of data with all kinds of bit patterns
+    fn page() -> Vec<u8> {
+        (0..FAST_PAGE_SIZE).map(|index| index as u8).collect()
+    }
+
+    #[test]
+    fn program_and_verify() {
+        let mut algorithm = algorithm();
+        let data = page();
+
+        algorithm.program_page(FLASH_BASE, &data).unwrap();
+
+        assert_eq!(
+            algorithm.verify(FLASH_BASE, &data),
+            FLASH_BASE + data.len() as u32
+        );
+        assert_eq!(&algorithm.flash.memory[..data.len()], &data[..]);
+    }
+
+    #[test]
+    fn programming_only_clears_bits() {
+        let mut algorithm = algorithm();
+
+        algorithm
+            .program_page(FLASH_BASE, &[0x0f; FAST_PAGE_SIZE])
+            .unwrap();
+
+        assert_eq!(
+            algorithm.program_page(FLASH_BASE, &[0xf0; FAST_PAGE_SIZE]),
+            Err(Error::Program)
+        );
+        assert_eq!(algorithm.flash.memory[0], 0x00);
+        assert_eq!(algorithm.last_error().code, Error::Program as i32);
+    }
+
+    #[test]
+    fn any_size_and_buffer_alignment_is_programmed() {
+        // Room to start the data at every offset into a word
+        let source: Vec<u8> = (0..FAST_PAGE_SIZE * 2).map(|index| !index as u8).collect();
+
+        for size in 1..=260 {
+            for buffer_offset in 0..4 {
+                let mut algorithm = algorithm();
+                let data = &source[buffer_offset..buffer_offset + size];
+
+                algorithm.program_page(FLASH_BASE, data).unwrap();
+
+                let memory = &algorithm.flash.memory;
+                assert_eq!(&memory[..size], data, "{size} bytes at {buffer_offset}");
+                // The padding leaves the rest of the last half-word or page erased
+                assert!(
+                    memory[size..FAST_PAGE_SIZE * 2].iter().all(|&b| b == EMPTY),
+                    "{size} bytes at {buffer_offset}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "fast-program"))]
+    fn half_words_are_programmed_in_pg_mode() {
+        let mut algorithm = algorithm();
+        algorithm.flash.take_log();
+
+        algorithm
+            .program_page(FLASH_BASE, &[0x00, 0x01, 0x02])
+            .unwrap();
+
+        assert_eq!(
+            algorithm.flash.take_log(),
+            [
+                Access::Mode(Mode::Program, true),
+                Access::WriteU16(FLASH_BASE, 0x0100),
+                Access::Busy(false),
+                Access::Status,
+                Access::ClearStatus,
+                // Padded with an erased byte
+                Access::WriteU16(FLASH_BASE + 2, 0xff02),
+                Access::Busy(false),
+                Access::Status,
+                Access::ClearStatus,
+                Access::Mode(Mode::Program, false),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "half-word written to flash in None")]
+    fn half_words_need_pg_mode() {
+        let mut algorithm = algorithm();
+
+        algorithm.flash.write_u16(FLASH_BASE, 0x0100);
+    }
+
+    #[test]
+    #[should_panic(expected = "word written to flash outside of fast mode")]
+    fn pg_mode_only_takes_half_words() {
+        let mut algorithm = algorithm();
+        algorithm.flash.set_mode(Mode::Program, true);
+
+        algorithm.flash.write_u32(FLASH_BASE, 0x0302_0100);
+    }
+
+    #[test]
+    #[cfg(feature = "fast-program")]
+    fn fast_programming_fills_the_buffer() {
+        let mut algorithm = algorithm();
+        algorithm.flash.take_log();
+
+        algorithm.program_page(FLASH_BASE, &page()).unwrap();
+
+        let log = algorithm.flash.take_log();
+        let commands: Vec<_> = log
+            .iter()
+            .filter(|access| {
+                matches!(
+                    access,
+                    Access::ResetBuffer | Access::LoadBuffer | Access::Start
+                )
+            })
+            .collect();
+        let mut expected = vec![&Access::ResetBuffer];
+        expected.extend([&Access::LoadBuffer; FAST_PAGE_SIZE / 16]);
+        expected.push(&Access::Start);
+        assert_eq!(commands, expected);
+        // Four words before each BUFLOAD
+        let blocks = log.split(|access| *access == Access::LoadBuffer);
+        for block in blocks.take(FAST_PAGE_SIZE / 16) {
+            let words = block.iter().filter(|a| matches!(a, Access::WriteU32(..)));
+            assert_eq!(words.count(), 4);
+        }
+    }
+
+    /// Initialized algorithm with FTPG set, but nothing loaded
+    fn fast_mode() -> Algorithm<SimFlash, SimClock> {
+        let mut algorithm = algorithm();
+        algorithm.unlock_fast().unwrap();
+        algorithm.flash.set_mode(Mode::FastProgram, true);
+        algorithm
+    }
+
+    /// Write four words of `value` into `block` of the page buffer at [`FLASH_BASE`]
+    fn load_block(algorithm: &mut Algorithm<SimFlash, SimClock>, block: u32, value: u32) {
+        for word in 0..4 {
+            let address = FLASH_BASE + block * 16 + word * 4;
+            algorithm.flash.write_u32(address, value);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "BUFLOAD before BUFRST")]
+    fn the_buffer_is_reset_before_loading() {
+        let mut algorithm = fast_mode();
+
+        load_block(&mut algorithm, 0, 0);
+        algorithm.flash.load_buffer();
+    }
+
+    #[test]
+    #[should_panic(expected = "STRT after 15 of 16 BUFLOADs")]
+    fn the_whole_buffer_is_loaded_before_programming() {
+        let mut algorithm = fast_mode();
+        algorithm.flash.reset_buffer();
+
+        for block in 0..15 {
+            load_block(&mut algorithm, block, 0);
+            algorithm.flash.load_buffer();
+        }
+        algorithm.flash.set_address(FLASH_BASE);
+        algorithm.flash.start();
+    }
+
+    #[test]
+    fn words_only_reach_the_buffer_with_bufload() {
+        let mut algorithm = fast_mode();
+        algorithm.flash.reset_buffer();
+
+        for block in 0..16 {
+            load_block(&mut algorithm, block, 0);
+            algorithm.flash.load_buffer();
+        }
+        // Written, but never loaded
+        load_block(&mut algorithm, 0, 0x5555_5555);
+        algorithm.flash.set_address(FLASH_BASE);
+        algorithm.flash.start();
+
+        assert!(algorithm.flash.memory[..FAST_PAGE_SIZE]
+            .iter()
+            .all(|&b| b == 0));
+    }
+
+    #[test]
+    fn misaligned_destinations_are_rejected() {
+        let mut algorithm = algorithm();
+        let mut misaligned = vec![FLASH_BASE + 1, FLASH_BASE + 3];
+        if cfg!(feature = "fast-program") {
+            // Fast programming only writes whole pages
+            misaligned.extend([FLASH_BASE + 2, FLASH_BASE + 4, FLASH_BASE + 0x80]);
+        }
+
+        for adr in misaligned {
+            assert_eq!(
+                algorithm.program_page(adr, &page()),
+                Err(Error::Misaligned),
+                "{adr:#x}"
+            );
+            assert_eq!(algorithm.last_error().address, adr);
+        }
+        assert!(algorithm.flash.started.is_empty());
+        assert!(algorithm.flash.memory.iter().all(|&b| b == EMPTY));
+    }
+
+    #[test]
+    fn erase_sector_empties_the_sector() {
+        let mut algorithm = algorithm();
+        let data = page();
+        algorithm.program_page(FLASH_BASE, &data).unwrap();
+
+        algorithm.erase_sector(FLASH_BASE).unwrap();
+
+        assert_eq!(algorithm.flash.started.last(), Some(&ERASE_MODE));
+        algorithm.blank_check(FLASH_BASE, ERASE_SIZE).unwrap();
+    }
+
+    #[test]
+    fn erase_sector_drives_the_controller() {
+        let mut algorithm = algorithm();
+        algorithm.flash.busy_polls = 2;
+        algorithm.flash.take_log();
+        let adr = FLASH_BASE + ERASE_SIZE;
+
+        algorithm.erase_sector(adr).unwrap();
+
+        assert_eq!(
+            algorithm.flash.take_log(),
+            [
+                Access::Mode(ERASE_MODE, true),
+                Access::Address(adr),
+                Access::Start,
+                // BSY is polled until the operation ends
+                Access::Busy(true),
+                Access::Busy(true),
+                Access::Busy(false),
+                // EOP and WRPRTERR are read, then cleared
+                Access::Status,
+                Access::ClearStatus,
+                Access::Mode(ERASE_MODE, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn erase_chip_empties_the_flash() {
+        let mut algorithm = algorithm();
+        let last_page = FLASH_BASE + DEVICE_SIZE - FAST_PAGE_SIZE as u32;
+        algorithm.program_page(last_page, &page()).unwrap();
+
+        algorithm.erase_chip().unwrap();
+
+        algorithm.blank_check(FLASH_BASE, DEVICE_SIZE).unwrap();
+    }
+
+    #[test]
+    fn blank_check_finds_the_first_programmed_byte() {
+        let mut algorithm = algorithm();
+        algorithm.flash.memory[0x123] = 0x7f;
+
+        assert_eq!(
+            algorithm.blank_check(FLASH_BASE + 0x121, 0x10),
+            Err(Error::VerifyMismatch)
+        );
+        assert_eq!(algorithm.last_error().address, FLASH_BASE + 0x123);
+    }
+
+    #[test]
+    fn blank_check_covers_unaligned_ranges() {
+        let mut algorithm = algorithm();
+        // Programmed bytes right before and after the range
+        algorithm.flash.memory[0x100 - 1] = 0x00;
+
+        for start in 0x100..0x104 {
+            for len in 0..12 {
+                let end = start + len;
+                algorithm.flash.memory[end] = 0x00;
+                assert_eq!(
+                    algorithm.blank_check(FLASH_BASE + start as u32, len as u32),
+                    Ok(()),
+                    "{len} bytes at {start:#x}"
+                );
+
+                if len > 0 {
+                    // The last byte of the range is checked as well
+                    algorithm.flash.memory[end - 1] = 0x00;
+                    assert_eq!(
+                        algorithm.blank_check(FLASH_BASE + start as u32, len as u32),
+                        Err(Error::VerifyMismatch),
+                        "{len} bytes at {start:#x}"
+                    );
+                    assert_eq!(algorithm.last_error().address, FLASH_BASE + end as u32 - 1);
+                    algorithm.flash.memory[end - 1] = EMPTY;
+                }
+                algorithm.flash.memory[end] = EMPTY;
+            }
+        }
+    }
+
+    #[test]
+    fn blank_check_reaches_the_end_of_flash() {
+        let mut algorithm = algorithm();
+        algorithm.flash.memory[DEVICE_SIZE as usize - 1] = 0xfe;
+
+        assert_eq!(
+            algorithm.blank_check(FLASH_BASE, DEVICE_SIZE),
+            Err(Error::VerifyMismatch)
+        );
+        assert_eq!(algorithm.last_error().address, FLASH_BASE + DEVICE_SIZE - 1);
+    }
+
+    #[test]
+    fn blank_check_rejects_ranges_outside_of_flash() {
+        let mut algorithm = algorithm();
+
+        for (adr, sz) in [
+            (FLASH_BASE - 1, 2),
+            (FLASH_BASE + DEVICE_SIZE - 4, 8),
+            (FLASH_BASE + DEVICE_SIZE, 1),
+            // adr + sz wraps around
+            (FLASH_BASE + 0x10, u32::MAX - 0x8),
+            (u32::MAX - 1, 4),
+        ] {
+            assert_eq!(
+                algorithm.blank_check(adr, sz),
+                Err(Error::OutOfRange),
+                "{sz:#x} bytes at {adr:#x}"
+            );
+            assert_eq!(algorithm.last_error().address, adr);
+        }
+    }
+
+    #[test]
+    fn verify_reports_the_first_mismatch() {
+        let mut algorithm = algorithm();
+        algorithm.program_page(FLASH_BASE, &page()).unwrap();
+        let mut expected = page();
+        expected[10] ^= 1;
+
+        assert_eq!(algorithm.verify(FLASH_BASE, &expected), FLASH_BASE + 10);
+        assert_eq!(algorithm.last_error().code, Error::VerifyMismatch as i32);
+    }
+
+    #[test]
+    fn verify_finds_mismatches_at_either_end() {
+        let mut algorithm = algorithm();
+        algorithm.program_page(FLASH_BASE, &page()).unwrap();
+
+        for index in [0, FAST_PAGE_SIZE - 1] {
+            let mut expected = page();
+            expected[index] ^= 0x80;
+
+            let address = FLASH_BASE + index as u32;
+            assert_eq!(algorithm.verify(FLASH_BASE, &expected), address);
+            assert_eq!(algorithm.last_error().address, address);
+        }
+    }
+
+    #[test]
+    fn read_copies_the_flash() {
+        let mut algorithm = algorithm();
+        algorithm.program_page(FLASH_BASE, &page()).unwrap();
+        let mut data = [0; 8];
+
+        algorithm.read(FLASH_BASE + 0xfc, &mut data).unwrap();
+
+        assert_eq!(data, [0xfc, 0xfd, 0xfe, 0xff, EMPTY, EMPTY, EMPTY, EMPTY]);
+        assert_eq!(
+            algorithm.read(FLASH_BASE + DEVICE_SIZE - 4, &mut data),
+            Err(Error::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn calls_without_init_fail() {
+        let mut algorithm = Algorithm::new(SimFlash::new(DEVICE_SIZE), SimClock::new());
+
+        assert_eq!(algorithm.erase_sector(FLASH_BASE), Err(Error::BadCallOrder));
+        assert_eq!(
+            algorithm.program_page(FLASH_BASE, &page()),
+            Err(Error::BadCallOrder)
+        );
+        assert_eq!(algorithm.erase_chip(), Err(Error::BadCallOrder));
+        assert_eq!(
+            algorithm.read(FLASH_BASE, &mut [0; 4]),
+            Err(Error::BadCallOrder)
+        );
+        assert_eq!(algorithm.uninit(), Err(Error::BadCallOrder));
+        assert!(algorithm.flash.locked());
+    }
+
+    #[test]
+    fn init_clears_the_last_error() {
+        let mut algorithm = Algorithm::new(SimFlash::new(DEVICE_SIZE), SimClock::new());
+        algorithm.erase_chip().unwrap_err();
+
+        algorithm.init().unwrap();
+
+        assert_eq!(algorithm.last_error().code, 0);
+    }
+
+    #[test]
+    fn a_failed_init_hands_back_the_clocks() {
+        let mut algorithm = Algorithm::new(SimFlash::new(DEVICE_SIZE), SimClock::new());
+        // A wrong key keeps the controller locked until the next reset
+        algorithm.flash.write_key(0);
+
+        assert_eq!(algorithm.init(), Err(Error::Locked));
+        assert!(!algorithm.clock.initialized());
+        assert_eq!(algorithm.init_option_bytes(), Err(Error::Locked));
+        assert!(!algorithm.clock.initialized());
+        // Handing back the clocks doesn't replace the error
+        assert_eq!(algorithm.last_error().code, Error::Locked as i32);
+    }
+
+    #[test]
+    fn addresses_outside_of_flash_are_rejected() {
+        let mut algorithm = algorithm();
+
+        assert_eq!(
+            algorithm.erase_sector(FLASH_BASE - ERASE_SIZE),
+            Err(Error::OutOfRange)
+        );
+        assert_eq!(
+            algorithm.erase_sector(FLASH_BASE + DEVICE_SIZE),
+            Err(Error::OutOfRange)
+        );
+        assert_eq!(
+            algorithm.program_page(FLASH_BASE + DEVICE_SIZE, &page()),
+            Err(Error::OutOfRange)
+        );
+        assert_eq!(algorithm.last_error().address, FLASH_BASE + DEVICE_SIZE);
+        assert!(algorithm.flash.started.is_empty());
+    }
+
+    #[test]
+    fn flash_is_limited_by_the_capacity() {
+        let mut algorithm = Algorithm::new(SimFlash::new(128 * 1024), SimClock::new());
+        algorithm.init().unwrap();
+
+        assert_eq!(
+            algorithm.program_page(FLASH_BASE + 128 * 1024, &page()),
+            Err(Error::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn esig_capacity_limits_erase_and_program() {
+        let mut algorithm = Algorithm::new(SimFlash::new(DEVICE_SIZE), SimClock::new());
+        // A part with less flash than the device describes
+        algorithm.flash.flacap = 64;
+        algorithm.init().unwrap();
+        let end = FLASH_BASE + 64 * 1024;
+
+        algorithm.erase_sector(end - ERASE_SIZE).unwrap();
+        let last_page = end - FAST_PAGE_SIZE as u32;
+        algorithm.program_page(last_page, &page()).unwrap();
+
+        assert_eq!(algorithm.erase_sector(end), Err(Error::OutOfRange));
+        assert_eq!(algorithm.last_error().code, 6);
+        assert_eq!(
+            algorithm.program_page(last_page, &[0x00; FAST_PAGE_SIZE + 2]),
+            Err(Error::OutOfRange)
+        );
+        assert_eq!(algorithm.last_error().address, last_page);
+        assert!(algorithm.flash.memory[64 * 1024..]
+            .iter()
+            .all(|&b| b == EMPTY));
+    }
+
+    #[test]
+    #[cfg(not(feature = "flash-480k"))]
+    fn sram_code_mode_limits_erase_and_program() {
+        for mode in [0b00, 0b01] {
+            let mut algorithm = Algorithm::new(SimFlash::new(DEVICE_SIZE), SimClock::new());
+            // Zero-wait flash traded for SRAM
+            algorithm.flash.sram_code_mode = mode;
+            algorithm.init().unwrap();
+            let end = FLASH_BASE + CHIP.code_sizes[mode as usize].min(DEVICE_SIZE);
+
+            algorithm.erase_sector(end - ERASE_SIZE).unwrap();
+            let last_page = end - FAST_PAGE_SIZE as u32;
+            algorithm.program_page(last_page, &page()).unwrap();
+
+            for adr in [FLASH_BASE + 192 * 1024, FLASH_BASE + 224 * 1024] {
+                if adr < end {
+                    continue;
+                }
+                assert_eq!(
+                    algorithm.erase_sector(adr),
+                    Err(Error::OutOfRange),
+                    "{adr:#x} in mode {mode:#04b}"
+                );
+                assert_eq!(
+                    algorithm.program_page(adr, &page()),
+                    Err(Error::OutOfRange),
+                    "{adr:#x} in mode {mode:#04b}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "flash-480k")]
+    fn flash_480k_ignores_the_sram_code_mode() {
+        let mut algorithm = Algorithm::new(SimFlash::new(DEVICE_SIZE), SimClock::new());
+        algorithm.flash.sram_code_mode = 0b00;
+        algorithm.init().unwrap();
+
+        // Behind the 192 KB of zero-wait flash
+        let adr = FLASH_BASE + 224 * 1024;
+        algorithm.erase_sector(adr).unwrap();
+        algorithm.program_page(adr, &page()).unwrap();
+        let last_page = FLASH_BASE + DEVICE_SIZE - FAST_PAGE_SIZE as u32;
+        algorithm.program_page(last_page, &page()).unwrap();
+    }
+
+    #[test]
+    fn write_protected_flash_is_left_alone() {
+        let mut algorithm = algorithm();
+        algorithm.flash.memory[0] = 0x00;
+        // Protect the first group
+        algorithm.flash.wpr = !1;
+
+        assert_eq!(
+            algorithm.erase_sector(FLASH_BASE),
+            Err(Error::WriteProtected)
+        );
+        assert_eq!(
+            algorithm.program_page(FLASH_BASE, &page()),
+            Err(Error::WriteProtected)
+        );
+        assert_eq!(algorithm.flash.memory[0], 0x00);
+        assert!(algorithm.flash.started.is_empty());
+    }
+
+    #[test]
+    fn busy_controller_times_out() {
+        let mut algorithm = algorithm();
+        algorithm.flash.busy_polls = u32::MAX;
+
+        assert_eq!(algorithm.erase_sector(FLASH_BASE), Err(Error::Timeout));
+        assert_eq!(algorithm.last_error().address, FLASH_BASE);
+        // STATR.BSY is part of the report
+        assert_eq!(algorithm.last_error().statr & 1, 1);
+    }
+
+    #[test]
+    fn slow_operations_finish_in_time() {
+        let mut algorithm = algorithm();
+        algorithm.flash.busy_polls = 1000;
+
+        algorithm.program_page(FLASH_BASE, &page()).unwrap();
+        algorithm.erase_sector(FLASH_BASE).unwrap();
+    }
+
+    #[test]
+    fn watchdog_is_fed_while_waiting() {
+        let mut algorithm = algorithm();
+        algorithm.clock.watchdog = Some(100);
+        // Each operation takes a lot longer than the watchdog period
+        algorithm.flash.busy_polls = 10_000;
+
+        algorithm.erase_chip().unwrap();
+        algorithm.erase_sector(FLASH_BASE).unwrap();
+        algorithm.program_page(FLASH_BASE, &page()).unwrap();
+
+        assert!(!algorithm.clock.watchdog_reset.get());
+    }
+
+    #[test]
+    fn watchdog_resets_unless_fed() {
+        let mut clock = SimClock::new();
+        clock.watchdog = Some(100);
+
+        let deadline = clock.deadline(1);
+        while !clock.expired(deadline) {}
+
+        assert!(clock.watchdog_reset.get());
+    }
+}
@@ -0,0 +1,117 @@
+#![no_std]
+#![no_main]
+// Flash loader for the option bytes of the CH32V307 and the other parts in `chip`
+//
+// The option bytes are eight half-words at 0x1FFFF800: RDPR, USER, DATA0, DATA1 and WRPR0-3.
+// The low byte of each half-word holds the value, the high byte its complement. The controller
+// only accepts an option byte if both match, so this loader generates the complement itself.
+
+use ch32v307_flashloader::algorithm::Algorithm;
+#[cfg(not(feature = "gd32vf103"))]
+use ch32v307_flashloader::ch32v307::{Clock, Flash, Rcc};
+use ch32v307_flashloader::device::FlashDeviceDescription;
+use ch32v307_flashloader::error::{status, LastError};
+#[cfg(feature = "gd32vf103")]
+use ch32v307_flashloader::gd32vf103::{Clock, Flash, Rcu as Rcc};
+use ch32v307_flashloader::option_bytes::OPTION_BYTES_DEVICE;
+use core::ptr::addr_of_mut;
+use core::slice;
+
+// A panic would leave the loader spinning until the host times out. Release builds must not
+// contain any, panic-never turns every panic that survives optimization into a link error.
+// Without optimizations the bounds checks stay, so debug builds abort instead.
+#[cfg(debug_assertions)]
+use panic_abort as _;
+#[cfg(not(debug_assertions))]
+use panic_never as _;
+
+/// Segger tools require the PrgData section to exist in the target binary
+///
+/// They also scan the flashloader binary for this symbol to determine the section location
+/// If they cannot find it, the tool exits. This variable serves no other purpose.
+#[allow(non_upper_case_globals)]
+#[no_mangle]
+#[used]
+#[link_section = "PrgData"]
+pub static PRGDATA_Start: usize = 0;
+
+/// State kept between the calls, the linker script places it in PrgData
+static mut ALGORITHM: Algorithm<Flash, Clock> = Algorithm::new(Flash, Clock::new(Rcc));
+
+fn algorithm() -> &'static mut Algorithm<Flash, Clock> {
+    // The functions are called one at a time by the debug probe
+    unsafe { &mut *addr_of_mut!(ALGORITHM) }
+}
+
+/// Erase all option bytes
+///
+/// Erased option bytes read as 0xFFFF, which has RDPR enable read protection from the next
+/// reset on. Program the option bytes again right after erasing them.
+///
+/// `Return` - 0 on success, an error code otherwise.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn EraseSector(adr: u32) -> i32 {
+    status(algorithm().erase_option_bytes(adr))
+}
+
+/// Initializes the microcontroller for Flash programming. Returns 0 on Success, an error code otherwise
+///
+/// This is invoked whenever an attempt is made to download the program to Flash.
+///
+///  # Arguments
+///
+/// `adr` - specifies the base address of the device.
+///
+/// `clk` - specifies the clock frequency for prgramming the device.
+///
+/// `fnc` - is a number: 1=Erase, 2=Program, 3=Verify, to perform different init based on command
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn Init(_adr: u32, _clk: u32, _fnc: u32) -> i32 {
+    status(algorithm().init_option_bytes())
+}
+
+/// Program the option bytes in `sz` bytes from `buf` at `adr`
+///
+/// Only the low byte of every half-word in `buf` is used, the complement in the high byte is
+/// generated. Half-words given as 0xFFFF are left erased.
+///
+/// `Return` - 0 on success, an error code otherwise.
+///
+/// # Safety
+///
+/// `buf` must point to `sz` readable bytes. The debug probe guarantees that.
+#[no_mangle]
+#[inline(never)]
+pub unsafe extern "C" fn ProgramPage(adr: u32, sz: u32, buf: *const u8) -> i32 {
+    let data = slice::from_raw_parts(buf, sz as usize);
+
+    status(algorithm().program_option_bytes(adr, data))
+}
+
+/// De-initializes the microcontroller after Flash programming. Returns 0 on Success, an error code otherwise
+///
+/// This is invoked at the end of an erasing, programming, or verifying step.
+///
+///  # Arguments
+///
+/// `fnc` - is a number: 1=Erase, 2=Program, 3=Verify, to perform different de-init based on command
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn UnInit(_fnc: u32) -> i32 {
+    // Restoring the flash controller also clears OBWRE again
+    status(algorithm().uninit())
+}
+
+/// Details on the last failure of any of the functions above, see `GetLastError` of the main loader
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn GetLastError() -> *const LastError {
+    algorithm().last_error()
+}
+
+#[allow(non_upper_case_globals)]
+#[no_mangle]
+#[link_section = "DeviceData"]
+pub static FlashDevice: FlashDeviceDescription = OPTION_BYTES_DEVICE;
@@ -0,0 +1,275 @@
+//! The CH32V307 itself, through `ch32v307_pac`
+//!
+//! The other parts in [`chip`](crate::chip) have the same FLASH, RCC, SysTick and watchdog
+//! registers, so this serves them as well.
+
+use crate::chip::CHIP;
+use crate::clock;
+use crate::hal::{ClockRegisters, FlashController, Mode, WatchdogRegisters};
+use crate::timer;
+use ch32v307_pac::flash::RegisterBlock;
+use ch32v307_pac::{EXTEND, FLASH, IWDG, RCC, WWDG};
+use riscv::register::mstatus;
+
+/// Flash capacity in KB, part of the electronic signature
+const ESIG_FLACAP: *const u16 = 0x1FFF_F7E0 as *const u16;
+/// SysTick control register
+const STK_CTLR: *mut u32 = 0xE000_F000 as *mut u32;
+/// Lower half of the SysTick counter
+const STK_CNTL: *const u32 = 0xE000_F008 as *const u32;
+/// Upper half of the SysTick counter
+const STK_CNTH: *const u32 = 0xE000_F00C as *const u32;
+/// STK_CTLR.STE, counter enable
+const STK_CTLR_STE: u32 = 1 << 0;
+/// STK_CTLR.STCLK, count on HCLK instead of HCLK/8
+const STK_CTLR_STCLK: u32 = 1 << 2;
+
+/// The FLASH peripheral and the memory behind it
+pub struct Flash;
+
+impl Flash {
+    fn registers(&self) -> &RegisterBlock {
+        unsafe { &(*FLASH::ptr()) }
+    }
+}
+
+/// FLASH_CTLR bit selecting `mode`
+const fn mode_bit(mode: Mode) -> u32 {
+    match mode {
+        Mode::Program => 1 << 0,
+        Mode::PageErase => 1 << 1,
+        Mode::MassErase => 1 << 2,
+        Mode::OptionBytesProgram => 1 << 4,
+        Mode::OptionBytesErase => 1 << 5,
+        Mode::FastProgram => 1 << 16,
+        Mode::Erase256 => 1 << 17,
+        Mode::Erase32k => 1 << 23,
+    }
+}
+
+impl FlashController for Flash {
+    fn locked(&self) -> bool {
+        self.registers().ctlr.read().lock().bit_is_set()
+    }
+
+    fn write_key(&mut self, key: u32) {
+        self.registers().keyr.write(|w| unsafe { w.bits(key) })
+    }
+
+    fn fast_locked(&self) -> bool {
+        self.registers().ctlr.read().flock().bit_is_set()
+    }
+
+    fn write_fast_key(&mut self, key: u32) {
+        self.registers().modekeyr.write(|w| unsafe { w.bits(key) })
+    }
+
+    fn option_bytes_unlocked(&self) -> bool {
+        self.registers().ctlr.read().obwre().bit_is_set()
+    }
+
+    fn write_option_bytes_key(&mut self, key: u32) {
+        self.registers().obkeyr.write(|w| unsafe { w.bits(key) })
+    }
+
+    fn set_mode(&mut self, mode: Mode, enabled: bool) {
+        let bit = mode_bit(mode);
+        self.registers().ctlr.modify(|r, w| unsafe {
+            if enabled {
+                w.bits(r.bits() | bit)
+            } else {
+                w.bits(r.bits() & !bit)
+            }
+        });
+    }
+
+    fn set_address(&mut self, address: u32) {
+        self.registers().addr.write(|w| unsafe { w.bits(address) });
+    }
+
+    fn start(&mut self) {
+        self.registers().ctlr.modify(|_, w| w.strt().set_bit());
+    }
+
+    fn reset_buffer(&mut self) {
+        self.registers().ctlr.modify(|_, w| w.bufrst().set_bit());
+    }
+
+    fn load_buffer(&mut self) {
+        self.registers().ctlr.modify(|_, w| w.bufload().set_bit());
+    }
+
+    fn busy(&self) -> bool {
+        self.registers().statr.read().bsy().bit_is_set()
+    }
+
+    fn status(&self) -> u32 {
+        self.registers().statr.read().bits()
+    }
+
+    fn clear_status(&mut self) {
+        // EOP and WRPRTERR are cleared by writing 1
+        self.registers()
+            .statr
+            .write(|w| w.wrprterr().set_bit().eop().set_bit());
+    }
+
+    fn write_protected_groups(&self) -> u32 {
+        // FLASH_WPR mirrors WRPR0-3, a cleared bit means the group is protected
+        !self.registers().wpr.read().bits()
+    }
+
+    fn read_protected(&self) -> bool {
+        self.registers().obr.read().rdprt().bit_is_set()
+    }
+
+    fn sram_code_mode(&self) -> u8 {
+        // The PAC only knows OBR bits 0-7, SRAM_CODE_MODE is bits 8 and 9
+        ((self.registers().obr.read().bits() >> 8) & 0b11) as u8
+    }
+
+    fn capacity(&self) -> u32 {
+        let kilobytes = unsafe { ESIG_FLACAP.read_volatile() };
+        kilobytes as u32 * 1024
+    }
+
+    fn read_u8(&self, address: u32) -> u8 {
+        unsafe { (address as *const u8).read_volatile() }
+    }
+
+    fn read_u16(&self, address: u32) -> u16 {
+        unsafe { (address as *const u16).read_volatile() }
+    }
+
+    fn read_u32(&self, address: u32) -> u32 {
+        unsafe { (address as *const u32).read_volatile() }
+    }
+
+    fn write_u16(&mut self, address: u32, value: u16) {
+        unsafe { (address as *mut u16).write_volatile(value) }
+    }
+
+    fn write_u32(&mut self, address: u32, value: u32) {
+        unsafe { (address as *mut u32).write_volatile(value) }
+    }
+}
+
+/// RCC, together with the other registers switching the clocks involves and the watchdogs
+pub struct Rcc;
+
+impl ClockRegisters for Rcc {
+    /// SysTick counts on HCLK, 96000 ticks per ms on the CH32V307
+    const TICKS_PER_MS: u64 = CHIP.hclk as u64 / 1000;
+    const CFGR0_PLLMUL: u32 = 0b1111 << 18;
+    const PLLMUL: u32 = (CHIP.pll_mul as u32) << 18;
+    /// FLASH_ACTLR and EXTEND_CTR
+    type Saved = (u32, u32);
+
+    fn ctlr(&self) -> u32 {
+        rcc().ctlr.read().bits()
+    }
+
+    fn write_ctlr(&mut self, value: u32) {
+        rcc().ctlr.write(|w| unsafe { w.bits(value) });
+    }
+
+    fn cfgr0(&self) -> u32 {
+        rcc().cfgr0.read().bits()
+    }
+
+    fn write_cfgr0(&mut self, value: u32) {
+        rcc().cfgr0.write(|w| unsafe { w.bits(value) });
+    }
+
+    fn flash_ctlr(&self) -> u32 {
+        Flash.registers().ctlr.read().bits()
+    }
+
+    fn write_flash_ctlr(&mut self, value: u32) {
+        Flash.registers().ctlr.write(|w| unsafe { w.bits(value) });
+    }
+
+    fn save(&self) -> Self::Saved {
+        (
+            Flash.registers().actlr.read().bits(),
+            extend().extend_ctr.read().bits(),
+        )
+    }
+
+    fn prepare(&mut self) {
+        // Wait states for the raised HCLK
+        Flash
+            .registers()
+            .actlr
+            .modify(|_, w| unsafe { w.latency().bits(CHIP.latency) });
+        // Feed HSI undivided to the PLL, 8 MHz * 12 = 96 MHz on the CH32V307
+        extend().extend_ctr.modify(|_, w| w.pll_hsi_pre().set_bit());
+    }
+
+    fn restore(&mut self, saved: &Self::Saved) {
+        let (actlr, extend_ctr) = *saved;
+        Flash.registers().actlr.write(|w| unsafe { w.bits(actlr) });
+        extend().extend_ctr.write(|w| unsafe { w.bits(extend_ctr) });
+    }
+
+    /// Let SysTick run freely on HCLK
+    fn start_timer(&mut self) -> u32 {
+        unsafe {
+            let ctlr = STK_CTLR.read_volatile();
+            STK_CTLR.write_volatile(STK_CTLR_STCLK | STK_CTLR_STE);
+            ctlr
+        }
+    }
+
+    fn stop_timer(&mut self, control: u32) {
+        unsafe { STK_CTLR.write_volatile(control) };
+    }
+
+    fn now(&self) -> u64 {
+        unsafe { timer::read(STK_CNTL, STK_CNTH) }
+    }
+
+    fn disable_interrupts(&mut self) -> bool {
+        let mie = mstatus::read().mie();
+        unsafe { mstatus::clear_mie() };
+        mie
+    }
+
+    fn enable_interrupts(&mut self) {
+        unsafe { mstatus::set_mie() };
+    }
+}
+
+impl WatchdogRegisters for Rcc {
+    fn write_iwdg_ctlr(&mut self, key: u32) {
+        let iwdg = unsafe { &(*IWDG::ptr()) };
+        iwdg.ctlr.write(|w| unsafe { w.bits(key) });
+    }
+
+    fn wwdg_ctlr(&self) -> u32 {
+        wwdg().ctlr.read().bits()
+    }
+
+    fn write_wwdg_ctlr(&mut self, value: u32) {
+        wwdg().ctlr.write(|w| unsafe { w.bits(value) });
+    }
+
+    fn wwdg_cfgr(&self) -> u32 {
+        wwdg().cfgr.read().bits()
+    }
+}
+
+fn rcc() -> &'static ch32v307_pac::rcc::RegisterBlock {
+    unsafe { &(*RCC::ptr()) }
+}
+
+fn extend() -> &'static ch32v307_pac::extend::RegisterBlock {
+    unsafe { &(*EXTEND::ptr()) }
+}
+
+fn wwdg() -> &'static ch32v307_pac::wwdg::RegisterBlock {
+    unsafe { &(*WWDG::ptr()) }
+}
+
+/// RCC, SysTick and the watchdogs, switched by [`clock`]
+pub type Clock = clock::Clock<Rcc>;
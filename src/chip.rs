@@ -0,0 +1,227 @@
+//! Parameters of the parts the loaders can be built for
+//!
+//! The CH32V30x and CH32V20x share the FLASH controller, page layout and write protection
+//! groups, they differ in the amount of flash and how fast it may be clocked while programming.
+//! One of the features `ch32v303`, `ch32v305`, `ch32v317`, `ch32v203` or `ch32v208` selects
+//! [`CHIP`], the CH32V307 is the default.
+//!
+//! The GD32VF103 is a different chip with a controller of the same lineage, `gd32vf103` selects
+//! [`GD32VF103`] and the `gd32vf103` module instead of `ch32v307`.
+
+/// One member of the family
+pub struct Chip {
+    /// Part name, the start of `FlashDevice.dev_name`
+    pub name: &'static str,
+    /// Flash described by `FlashDevice` unless a size feature selects something else
+    pub flash_size: u32,
+    /// Zero-wait code flash for each value of SRAM_CODE_MODE
+    pub code_sizes: [u32; 4],
+    /// Bytes erased by PER, the standard page erase
+    pub page_size: u32,
+    /// HCLK in Hz while the loader runs, from the PLL fed by the 8 MHz HSI, or half of it on the
+    /// GD32VF103
+    pub hclk: u32,
+    /// RCC_CFGR0.PLLMUL for [`Self::hclk`], RCU_CFG0.PLLMF on the GD32VF103
+    pub pll_mul: u8,
+    /// FLASH_ACTLR.LATENCY for [`Self::hclk`], the GD32VF103 doesn't need wait states
+    pub latency: u8,
+}
+
+/// Zero-wait flash splits of the CH32V30x, SRAM gets the rest of the 320 KB
+const CH32V30X_CODE_SIZES: [u32; 4] = [192 * 1024, 224 * 1024, 256 * 1024, 288 * 1024];
+
+/// Zero-wait flash splits of the CH32V20x with 64 KB SRAM, parts with less flash have no split
+const CH32V20X_CODE_SIZES: [u32; 4] = [128 * 1024, 144 * 1024, 160 * 1024, 160 * 1024];
+
+pub const CH32V303: Chip = Chip {
+    name: "CH32V303",
+    ..CH32V307
+};
+
+pub const CH32V305: Chip = Chip {
+    name: "CH32V305",
+    ..CH32V307
+};
+
+/// Flash operations run at up to 100 MHz, so the PLL is set to 96 MHz
+pub const CH32V307: Chip = Chip {
+    name: "CH32V307",
+    flash_size: 256 * 1024,
+    code_sizes: CH32V30X_CODE_SIZES,
+    page_size: 0x1000,
+    hclk: 96_000_000,
+    // 8 MHz * 12
+    pll_mul: 0b1010,
+    // Two wait states above 48 MHz
+    latency: 0b010,
+};
+
+pub const CH32V317: Chip = Chip {
+    name: "CH32V317",
+    ..CH32V307
+};
+
+/// Flash operations run from a 48 MHz PLL
+pub const CH32V203: Chip = Chip {
+    name: "CH32V203",
+    flash_size: 128 * 1024,
+    code_sizes: CH32V20X_CODE_SIZES,
+    page_size: 0x1000,
+    hclk: 48_000_000,
+    // 8 MHz * 6
+    pll_mul: 0b0100,
+    // One wait state up to 48 MHz
+    latency: 0b001,
+};
+
+pub const CH32V208: Chip = Chip {
+    name: "CH32V208",
+    ..CH32V203
+};
+
+/// Flash operations run from a 108 MHz PLL, like in the C firmware
+///
+/// There is no SRAM_CODE_MODE, all of the flash is zero-wait.
+pub const GD32VF103: Chip = Chip {
+    name: "GD32VF103",
+    flash_size: 128 * 1024,
+    code_sizes: [128 * 1024; 4],
+    page_size: 0x400,
+    hclk: 108_000_000,
+    // IRC8M / 2 * 27
+    pll_mul: 0b11010,
+    latency: 0,
+};
+
+/// Every supported part of the CH32 family
+pub const CHIPS: [&Chip; 6] = [
+    &CH32V303, &CH32V305, &CH32V307, &CH32V317, &CH32V203, &CH32V208,
+];
+
+#[cfg(any(
+    all(feature = "ch32v303", feature = "ch32v305"),
+    all(feature = "ch32v303", feature = "ch32v317"),
+    all(feature = "ch32v303", feature = "ch32v203"),
+    all(feature = "ch32v303", feature = "ch32v208"),
+    all(feature = "ch32v305", feature = "ch32v317"),
+    all(feature = "ch32v305", feature = "ch32v203"),
+    all(feature = "ch32v305", feature = "ch32v208"),
+    all(feature = "ch32v317", feature = "ch32v203"),
+    all(feature = "ch32v317", feature = "ch32v208"),
+    all(feature = "ch32v203", feature = "ch32v208"),
+))]
+compile_error!("only one of the features `ch32v303`, `ch32v305`, `ch32v317`, `ch32v203` and `ch32v208` may be enabled");
+
+#[cfg(all(
+    feature = "gd32vf103",
+    any(
+        feature = "ch32v303",
+        feature = "ch32v305",
+        feature = "ch32v317",
+        feature = "ch32v203",
+        feature = "ch32v208"
+    )
+))]
+compile_error!("the feature `gd32vf103` can't be combined with the features of the CH32 parts");
+
+#[cfg(feature = "ch32v303")]
+pub const CHIP: Chip = CH32V303;
+#[cfg(feature = "ch32v305")]
+pub const CHIP: Chip = CH32V305;
+#[cfg(feature = "ch32v317")]
+pub const CHIP: Chip = CH32V317;
+#[cfg(feature = "ch32v203")]
+pub const CHIP: Chip = CH32V203;
+#[cfg(feature = "ch32v208")]
+pub const CHIP: Chip = CH32V208;
+#[cfg(feature = "gd32vf103")]
+pub const CHIP: Chip = GD32VF103;
+#[cfg(not(any(
+    feature = "ch32v303",
+    feature = "ch32v305",
+    feature = "ch32v317",
+    feature = "ch32v203",
+    feature = "ch32v208",
+    feature = "gd32vf103"
+)))]
+pub const CHIP: Chip = CH32V307;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithm::{DEVICE_NAME, WRP_GROUP_SIZE};
+    use crate::device::{dev_name, dev_name_str};
+
+    /// Frequency of HSI, the input of the PLL
+    const HSI: u32 = 8_000_000;
+    /// Highest HCLK the CH32V30x flash can be programmed with
+    const FLASH_HCLK_MAX: u32 = 100_000_000;
+
+    #[test]
+    fn descriptors_are_consistent() {
+        for chip in CHIPS {
+            let name = chip.name;
+
+            // PLLMUL counts from 2 in steps of 1, up to 16
+            let multiplier = chip.pll_mul as u32 + 2;
+            assert_eq!(HSI * multiplier, chip.hclk, "{name}");
+            assert!(chip.hclk <= FLASH_HCLK_MAX, "{name}");
+            let latency = match chip.hclk {
+                0..=24_000_000 => 0,
+                24_000_001..=48_000_000 => 1,
+                _ => 2,
+            };
+            assert_eq!(chip.latency, latency, "{name}");
+            assert_eq!(chip.page_size, 0x1000, "{name}");
+            // SysTick counts whole ticks per ms
+            assert_eq!(chip.hclk % 1000, 0, "{name}");
+
+            assert!(chip.code_sizes.windows(2).all(|w| w[0] <= w[1]), "{name}");
+            assert!(chip.flash_size <= chip.code_sizes[3], "{name}");
+            for size in chip.code_sizes.iter().chain([&chip.flash_size]) {
+                assert_eq!(size % 0x1000, 0, "{name}");
+                assert_eq!(size % WRP_GROUP_SIZE, 0, "{name}");
+            }
+            assert_eq!(chip.flash_size % 0x8000, 0, "{name}");
+            assert!(name.len() + DEVICE_NAME.len() < 127, "{name}");
+        }
+
+        let mut names: Vec<_> = CHIPS.iter().map(|chip| chip.name).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), CHIPS.len());
+    }
+
+    #[test]
+    fn gd32vf103_descriptor_is_consistent() {
+        let chip = GD32VF103;
+
+        // PLLMF counts from 2 up to 16, skips the second 16 and continues with 17
+        let pll_mf = chip.pll_mul as u32;
+        let multiplier = if pll_mf < 0b10000 {
+            pll_mf + 2
+        } else {
+            pll_mf + 1
+        };
+        assert_eq!(HSI / 2 * multiplier, chip.hclk);
+        assert!(chip.hclk <= 108_000_000);
+        // The core timer counts whole ticks per ms on HCLK / 4
+        assert_eq!(chip.hclk / 4 % 1000, 0);
+
+        assert!(chip.code_sizes.iter().all(|&size| size == chip.flash_size));
+        assert_eq!(chip.flash_size % WRP_GROUP_SIZE, 0);
+        assert_eq!(chip.flash_size % chip.page_size, 0);
+        assert!(chip.name.len() + DEVICE_NAME.len() < 127);
+        assert!(CHIPS.iter().all(|other| other.name != chip.name));
+    }
+
+    #[test]
+    fn device_name_starts_with_the_part() {
+        let name = dev_name(&[CHIP.name, " ", DEVICE_NAME]);
+        let len = CHIP.name.len() + 1 + DEVICE_NAME.len();
+
+        assert!(name.starts_with(CHIP.name.as_bytes()));
+        assert!(name[len..].iter().all(|&b| b == 0));
+        assert_eq!(dev_name_str(&name).len(), len);
+    }
+}
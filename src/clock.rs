@@ -0,0 +1,324 @@
+//! Clock setup for flash operations
+//!
+//! Like the C firmware, [`Clock::init`](ClockController::init) saves the state of the clock and
+//! flash controller so that [`Clock::uninit`](ClockController::uninit) can hand the chip back to
+//! the application exactly as it found it. The sequence only depends on the bits of CTLR and
+//! CFGR0 defined here, the chip provides the registers through [`ClockRegisters`].
+
+use crate::error::Error;
+use crate::hal::{ClockController, ClockRegisters};
+use crate::watchdog;
+
+/// CTLR.HSION
+pub const CTLR_HSION: u32 = 1 << 0;
+/// CTLR.HSIRDY
+pub const CTLR_HSIRDY: u32 = 1 << 1;
+/// CTLR.HSEON
+pub const CTLR_HSEON: u32 = 1 << 16;
+/// CTLR.HSERDY
+pub const CTLR_HSERDY: u32 = 1 << 17;
+/// CTLR.PLLON
+pub const CTLR_PLLON: u32 = 1 << 24;
+/// CTLR.PLLRDY
+pub const CTLR_PLLRDY: u32 = 1 << 25;
+/// CFGR0.SW, system clock switch
+pub const CFGR0_SW: u32 = 0b11;
+/// CFGR0.SWS, the clock the switch has selected
+pub const CFGR0_SWS: u32 = 0b11 << 2;
+/// CFGR0.HPRE, AHB prescaler
+pub const CFGR0_HPRE: u32 = 0b1111 << 4;
+/// CFGR0.PPRE1, APB1 prescaler
+pub const CFGR0_PPRE1: u32 = 0b111 << 8;
+/// CFGR0.PPRE2, APB2 prescaler
+pub const CFGR0_PPRE2: u32 = 0b111 << 11;
+/// CFGR0.PLLSRC, HSE instead of HSI as PLL input
+pub const CFGR0_PLLSRC: u32 = 1 << 16;
+/// CFGR0.PLLXTPRE, HSE predivider
+pub const CFGR0_PLLXTPRE: u32 = 1 << 17;
+/// CFGR0.SW selecting HSI
+pub const SW_HSI: u32 = 0b00;
+/// CFGR0.SW selecting the PLL
+pub const SW_PLL: u32 = 0b10;
+/// CFGR0.PPRE1 dividing HCLK by 2
+const PPRE1_DIV2: u32 = 0b100 << 8;
+
+/// Time in ms an oscillator or clock switch may take to get ready
+///
+/// The timer assumes the PLL is running, so from a slower clock the actual limit is longer.
+const CLOCK_TIME_OUT: u32 = 10;
+
+/// Registers captured by `init` and written back by `uninit`
+#[derive(Copy, Clone)]
+struct SavedState<S> {
+    ctlr: u32,
+    cfgr0: u32,
+    flash_ctlr: u32,
+    /// Whatever else the chip changes, see [`ClockRegisters::save`]
+    chip: S,
+    /// Control register of the time base
+    timer: u32,
+    /// mstatus.MIE on entry to Init
+    mie: bool,
+}
+
+/// Clocks, time base and watchdogs of a chip, switched to the PLL at
+/// [`Chip::hclk`](crate::chip::Chip::hclk) between `init` and `uninit`
+///
+/// The loaders keep it in a static, so the saved state ends up in PrgData.
+pub struct Clock<R: ClockRegisters> {
+    registers: R,
+    saved: Option<SavedState<R::Saved>>,
+}
+
+impl<R: ClockRegisters> Clock<R> {
+    pub const fn new(registers: R) -> Self {
+        Self {
+            registers,
+            saved: None,
+        }
+    }
+
+    /// Run the core from HSI and stop the PLL, which only takes new settings while it's off
+    fn stop_pll(&mut self) -> Result<(), Error> {
+        let ctlr = self.registers.ctlr();
+        self.registers.write_ctlr(ctlr | CTLR_HSION);
+        self.wait_until(|r| r.ctlr() & CTLR_HSIRDY != 0)?;
+        self.select(SW_HSI)?;
+        let ctlr = self.registers.ctlr();
+        self.registers.write_ctlr(ctlr & !CTLR_PLLON);
+        self.wait_until(|r| r.ctlr() & CTLR_PLLRDY == 0)
+    }
+
+    /// Switch SYSCLK to the clock `sw` selects and wait until it has been selected
+    fn select(&mut self, sw: u32) -> Result<(), Error> {
+        let cfgr0 = self.registers.cfgr0();
+        self.registers.write_cfgr0(cfgr0 & !CFGR0_SW | sw);
+        self.wait_until(|r| r.cfgr0() & CFGR0_SWS == sw << 2)
+    }
+
+    /// Write the clock and flash controller registers back, the timer is still needed for this
+    fn restore(&mut self, saved: &SavedState<R::Saved>) -> Result<(), Error> {
+        // Writing back CTLR re-locks the controller if it was locked before Init
+        self.registers.write_flash_ctlr(saved.flash_ctlr);
+
+        // Fall back to HSI so the PLL and flash latency can be changed safely
+        self.stop_pll()?;
+
+        self.registers.restore(&saved.chip);
+        // Prescalers and PLL configuration, but keep running from HSI for now
+        self.registers.write_cfgr0(saved.cfgr0 & !CFGR0_SW);
+        // Oscillator and PLL enables, HSI stays on until we've switched away from it
+        self.registers.write_ctlr(saved.ctlr | CTLR_HSION);
+        if saved.ctlr & CTLR_HSEON != 0 {
+            self.wait_until(|r| r.ctlr() & CTLR_HSERDY != 0)?;
+        }
+        if saved.ctlr & CTLR_PLLON != 0 {
+            self.wait_until(|r| r.ctlr() & CTLR_PLLRDY != 0)?;
+        }
+
+        self.select(saved.cfgr0 & CFGR0_SW)?;
+        self.registers.write_ctlr(saved.ctlr);
+
+        Ok(())
+    }
+
+    /// Poll `ready` until it returns true, for at most [`CLOCK_TIME_OUT`] ms
+    fn wait_until(&mut self, ready: impl Fn(&R) -> bool) -> Result<(), Error> {
+        let deadline = self.deadline(CLOCK_TIME_OUT);
+        while !ready(&self.registers) {
+            if self.expired(deadline) {
+                return Err(Error::Timeout);
+            }
+            watchdog::feed(&mut self.registers);
+        }
+        Ok(())
+    }
+}
+
+impl<R: ClockRegisters> ClockController for Clock<R> {
+    const TICKS_PER_MS: u64 = R::TICKS_PER_MS;
+
+    /// Save the clock and flash controller state, then run the core from the PLL
+    ///
+    /// Fails if an oscillator or the clock switch doesn't get ready in time. The state is saved
+    /// regardless, so `uninit` still restores it.
+    fn init(&mut self) -> Result<(), Error> {
+        // Nothing the application installed may run while the clocks are being switched
+        let mie = self.registers.disable_interrupts();
+        self.saved = Some(SavedState {
+            ctlr: self.registers.ctlr(),
+            cfgr0: self.registers.cfgr0(),
+            flash_ctlr: self.registers.flash_ctlr(),
+            chip: self.registers.save(),
+            timer: self.registers.start_timer(),
+            mie,
+        });
+
+        self.stop_pll()?;
+        // Wait states and PLL input for the raised HCLK
+        self.registers.prepare();
+        let cfgr0 = self.registers.cfgr0()
+            & !(CFGR0_HPRE | CFGR0_PPRE1 | CFGR0_PPRE2 | CFGR0_PLLSRC | CFGR0_PLLXTPRE);
+        // AHB and APB2 at HCLK, APB1 at half of it, the PLL fed by HSI. The HSE predivider is
+        // unused, keep it at reset value.
+        self.registers
+            .write_cfgr0(cfgr0 & !R::CFGR0_PLLMUL | PPRE1_DIV2 | R::PLLMUL);
+        let ctlr = self.registers.ctlr();
+        self.registers.write_ctlr(ctlr | CTLR_PLLON);
+        self.wait_until(|r| r.ctlr() & CTLR_PLLRDY != 0)?;
+        self.select(SW_PLL)
+    }
+
+    /// Restore everything `init` saved
+    ///
+    /// Fails without a matching call to `init`. If an oscillator doesn't get ready in time, the
+    /// core is left running from HSI.
+    fn uninit(&mut self) -> Result<(), Error> {
+        // Without a matching Init there is nothing to restore
+        let saved = match self.saved.take() {
+            Some(saved) => saved,
+            None => return Err(Error::BadCallOrder),
+        };
+
+        let result = self.restore(&saved);
+
+        self.registers.stop_timer(saved.timer);
+        if saved.mie {
+            self.registers.enable_interrupts();
+        }
+
+        result
+    }
+
+    fn initialized(&self) -> bool {
+        self.saved.is_some()
+    }
+
+    fn now(&self) -> u64 {
+        self.registers.now()
+    }
+
+    fn feed_watchdog(&mut self) {
+        watchdog::feed(&mut self.registers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithm::{Algorithm, DEVICE_SIZE};
+    use crate::chip::CHIP;
+    use crate::sim::{SimFlash, SimRcc};
+
+    /// CTLR, CFGR0, FLASH_CTLR, FLASH_ACTLR, STK_CTLR and MIE
+    fn state(rcc: &SimRcc) -> (u32, u32, u32, u32, u32, bool) {
+        (
+            rcc.ctlr(),
+            rcc.cfgr0(),
+            rcc.flash_ctlr,
+            rcc.flash_actlr,
+            rcc.stk_ctlr,
+            rcc.mie,
+        )
+    }
+
+    #[test]
+    fn init_runs_the_core_from_the_pll() {
+        let mut clock = Clock::new(SimRcc::new());
+
+        clock.init().unwrap();
+
+        let rcc = &clock.registers;
+        assert_eq!(rcc.cfgr0() & CFGR0_SWS, SW_PLL << 2);
+        assert_eq!(rcc.ctlr() & CTLR_PLLRDY, CTLR_PLLRDY);
+        assert_eq!(rcc.cfgr0() & SimRcc::CFGR0_PLLMUL, SimRcc::PLLMUL);
+        // HSI feeds the PLL
+        assert_eq!(rcc.cfgr0() & (CFGR0_PLLSRC | CFGR0_PLLXTPRE), 0);
+        assert_eq!(
+            rcc.cfgr0() & (CFGR0_HPRE | CFGR0_PPRE1 | CFGR0_PPRE2),
+            PPRE1_DIV2
+        );
+        assert_eq!(rcc.flash_actlr, CHIP.latency as u32);
+        assert!(!rcc.mie);
+    }
+
+    #[test]
+    fn a_running_pll_is_stopped_before_it_is_changed() {
+        let mut rcc = SimRcc::new();
+        // The application runs from the PLL fed by HSE, with other prescalers
+        rcc.ctlr |= CTLR_HSEON | CTLR_PLLON;
+        rcc.cfgr0 = SW_PLL | CFGR0_PLLSRC | 0b0110 << 18 | 0b1000 << 4;
+        let mut clock = Clock::new(rcc);
+
+        // The simulation panics if the PLL is changed while it runs
+        clock.init().unwrap();
+
+        let rcc = &clock.registers;
+        assert_eq!(rcc.cfgr0() & CFGR0_SWS, SW_PLL << 2);
+        assert_eq!(rcc.cfgr0() & SimRcc::CFGR0_PLLMUL, SimRcc::PLLMUL);
+        assert_eq!(rcc.cfgr0() & (CFGR0_PLLSRC | CFGR0_HPRE), 0);
+    }
+
+    #[test]
+    fn a_pll_that_does_not_lock_times_out() {
+        let mut rcc = SimRcc::new();
+        rcc.stuck = CTLR_PLLRDY;
+        let mut clock = Clock::new(rcc);
+
+        assert_eq!(clock.init(), Err(Error::Timeout));
+        // Still on HSI, the switch to the PLL never happened
+        assert_eq!(clock.registers.cfgr0() & CFGR0_SWS, SW_HSI << 2);
+        // The watchdogs were fed while waiting
+        assert!(clock.registers.iwdg_reloads > 0);
+    }
+
+    #[test]
+    fn a_failed_init_restores_the_clocks() {
+        let mut rcc = SimRcc::new();
+        rcc.stuck = CTLR_PLLRDY;
+        let before = state(&rcc);
+        let mut algorithm = Algorithm::new(SimFlash::new(DEVICE_SIZE), Clock::new(rcc));
+
+        assert_eq!(algorithm.init(), Err(Error::Timeout));
+
+        // There won't be an UnInit to do this
+        assert_eq!(state(&algorithm.clock.registers), before);
+        assert!(!algorithm.clock.initialized());
+    }
+
+    #[test]
+    fn uninit_restores_what_init_found() {
+        let from_reset = SimRcc::new();
+        // The application runs from the PLL fed by HSE, with interrupts off and one wait state
+        let mut from_pll = SimRcc::new();
+        from_pll.ctlr |= CTLR_HSEON | CTLR_PLLON;
+        from_pll.cfgr0 = SW_PLL | CFGR0_PLLSRC | 0b0110 << 18 | 0b1000 << 4 | 0b101 << 11;
+        from_pll.flash_actlr = 0b001;
+        from_pll.stk_ctlr = 0b1101;
+        from_pll.mie = false;
+
+        for rcc in [from_reset, from_pll] {
+            let before = state(&rcc);
+            let mut clock = Clock::new(rcc);
+            clock.init().unwrap();
+            // The algorithm unlocks the controller
+            clock.registers.flash_ctlr = 0;
+
+            clock.uninit().unwrap();
+
+            assert_eq!(state(&clock.registers), before);
+            assert!(!clock.initialized());
+        }
+    }
+
+    #[test]
+    fn uninit_needs_init() {
+        let mut clock = Clock::new(SimRcc::new());
+        assert_eq!(clock.uninit(), Err(Error::BadCallOrder));
+
+        clock.init().unwrap();
+        clock.uninit().unwrap();
+
+        assert_eq!(clock.uninit(), Err(Error::BadCallOrder));
+    }
+}
@@ -0,0 +1,137 @@
+//! Operations on the FLASH controller that all loaders need
+
+use crate::algorithm::Algorithm;
+use crate::error::Error;
+use crate::hal::{ClockController, FlashController, Mode};
+
+pub const FLASH_KEY1: u32 = 0x45670123;
+pub const FLASH_KEY2: u32 = 0xCDEF89AB;
+
+/// STATR.WRPRTERR
+pub const STATR_WRPRTERR: u32 = 1 << 4;
+
+/// Time in ms programming a half-word or page may take, `FlashDevice.program_time_out`
+pub const PROGRAM_TIME_OUT: u32 = 100;
+/// Time in ms erasing a sector may take, `FlashDevice.erase_time_out`
+pub const ERASE_TIME_OUT: u32 = 6000;
+
+/// Start of the option bytes
+pub const OB_BASE: u32 = 0x1FFF_F800;
+/// Eight half-words, each a value and its complement
+pub const OB_SIZE: u32 = 16;
+/// Offset of RDPR, read-out protection
+pub const OB_RDPR: u32 = 0;
+/// Offset of USER
+pub const OB_USER: u32 = 2;
+/// Offset of DATA0
+pub const OB_DATA0: u32 = 4;
+/// Offset of DATA1
+pub const OB_DATA1: u32 = 6;
+/// RDPR value that disables read-out protection
+pub const RDPR_UNPROTECTED: u8 = 0xa5;
+
+impl<F: FlashController, C: ClockController> Algorithm<F, C> {
+    /// Unlock the controller for programming and erasing
+    pub(crate) fn unlock(&mut self) -> Result<(), Error> {
+        if self.flash.locked() {
+            for key in [FLASH_KEY1, FLASH_KEY2] {
+                self.flash.write_key(key);
+            }
+        }
+        if self.flash.locked() {
+            return Err(self.fail(Error::Locked, 0));
+        }
+        Ok(())
+    }
+
+    /// Unlock fast programming and erase modes
+    ///
+    /// They have a lock of their own, opened with the same keys after [`Self::unlock`].
+    pub(crate) fn unlock_fast(&mut self) -> Result<(), Error> {
+        if self.flash.fast_locked() {
+            for key in [FLASH_KEY1, FLASH_KEY2] {
+                self.flash.write_fast_key(key);
+            }
+        }
+        if self.flash.fast_locked() {
+            return Err(self.fail(Error::Locked, 0));
+        }
+        Ok(())
+    }
+
+    /// Allow writes to the option bytes, requires [`Self::unlock`] first
+    pub(crate) fn unlock_option_bytes(&mut self) -> Result<(), Error> {
+        if !self.flash.option_bytes_unlocked() {
+            for key in [FLASH_KEY1, FLASH_KEY2] {
+                self.flash.write_option_bytes_key(key);
+            }
+        }
+        if !self.flash.option_bytes_unlocked() {
+            return Err(self.fail(Error::Locked, OB_BASE));
+        }
+        Ok(())
+    }
+
+    /// Wait until the flash controller is no longer busy, then clear its status flags
+    ///
+    /// Gives up after `time_out` ms, leaving the controller alone while it's still busy. `address`
+    /// is only used to report errors.
+    pub(crate) fn wait_for_flash(&mut self, address: u32, time_out: u32) -> Result<(), Error> {
+        let deadline = self.clock.deadline(time_out);
+        loop {
+            // Feed at least once, short operations add up over a whole page
+            self.clock.feed_watchdog();
+            if !self.flash.busy() {
+                break;
+            }
+            if self.clock.expired(deadline) {
+                return Err(self.fail(Error::Timeout, address));
+            }
+        }
+        self.clear_flash_status(address)
+    }
+
+    /// Clear the status flags of a finished flash operation
+    ///
+    /// Fails if the operation was rejected because of write protection. `address` is only used
+    /// to report errors.
+    pub(crate) fn clear_flash_status(&mut self, address: u32) -> Result<(), Error> {
+        // Keep the flags for the error report, they are gone once cleared
+        let statr = self.flash.status();
+        // Clear both before reporting the result
+        self.flash.clear_status();
+        if statr & STATR_WRPRTERR != 0 {
+            return Err(self.fail_with_statr(Error::WriteProtected, address, statr));
+        }
+        Ok(())
+    }
+
+    /// Run the operation of `mode` at `address` and wait up to `time_out` ms for it
+    pub(crate) fn run(&mut self, mode: Mode, address: u32, time_out: u32) -> Result<(), Error> {
+        self.flash.set_mode(mode, true);
+        self.flash.set_address(address);
+        self.flash.start();
+        let result = self.wait_for_flash(address, time_out);
+        self.flash.set_mode(mode, false);
+
+        result
+    }
+
+    /// Program the option byte at `adr` with `value`, the complement is generated
+    ///
+    /// Requires [`Self::unlock_option_bytes`] first.
+    pub(crate) fn program_option_byte(&mut self, adr: u32, value: u8) -> Result<(), Error> {
+        let half_word = u16::from_le_bytes([value, !value]);
+
+        self.flash.set_mode(Mode::OptionBytesProgram, true);
+        self.flash.write_u16(adr, half_word);
+        let result = self.wait_for_flash(adr, PROGRAM_TIME_OUT);
+        self.flash.set_mode(Mode::OptionBytesProgram, false);
+        result?;
+
+        if self.flash.read_u16(adr) != half_word {
+            return Err(self.fail(Error::Program, adr));
+        }
+        Ok(())
+    }
+}
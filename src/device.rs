@@ -0,0 +1,163 @@
+//! Layout of the `FlashDevice` description, as defined by the CMSIS-Pack flash algorithm
+//!
+//! The descriptions themselves are [`FLASH_DEVICE`](crate::algorithm::FLASH_DEVICE) and
+//! [`OPTION_BYTES_DEVICE`](crate::option_bytes::OPTION_BYTES_DEVICE).
+
+/// `FlashDevice.vers`, version 1.01 of the layout
+pub const VERSION: u16 = 0x0101;
+/// `FlashDevice.dev_type` of on-chip flash
+pub const ONCHIP: u16 = 1;
+
+#[repr(C)]
+pub struct FlashDeviceDescription {
+    pub vers: u16,
+    pub dev_name: [u8; 128],
+    pub dev_type: u16,
+    pub dev_addr: u32,
+    pub device_size: u32,
+    pub page_size: u32,
+    pub _reserved: u32,
+    pub empty: u8,
+    pub program_time_out: u32,
+    pub erase_time_out: u32,
+
+    pub flash_sectors: [FlashSector; 512],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct FlashSector {
+    pub size: u32,
+    pub address: u32,
+}
+
+impl FlashSector {
+    pub const fn default() -> Self {
+        FlashSector {
+            size: 0,
+            address: 0,
+        }
+    }
+}
+
+pub const SECTOR_END: FlashSector = FlashSector {
+    size: 0xffff_ffff,
+    address: 0xffff_ffff,
+};
+
+/// Sector table for a device made of equally sized sectors starting at address 0
+pub const fn uniform_sectors(size: u32) -> [FlashSector; 512] {
+    let mut sectors = [FlashSector::default(); 512];
+
+    sectors[0] = FlashSector { size, address: 0x0 };
+    sectors[1] = SECTOR_END;
+
+    sectors
+}
+
+/// Zero padded `dev_name` made of the strings in `parts`
+///
+/// Fails at compile time if the name doesn't leave room for the terminating zero.
+pub const fn dev_name(parts: &[&str]) -> [u8; 128] {
+    let mut dev_name = [0; 128];
+    let mut len = 0;
+    let mut part = 0;
+    while part < parts.len() {
+        let bytes = parts[part].as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            assert!(len < 127, "device name is longer than 127 bytes");
+            dev_name[len] = bytes[i];
+            len += 1;
+            i += 1;
+        }
+        part += 1;
+    }
+    dev_name
+}
+
+/// The name in a [`dev_name`] result, without the zero padding
+///
+/// For tools that take the name as a string, like the `algorithm!` macro of `flash-algorithm`.
+pub const fn dev_name_str(dev_name: &[u8; 128]) -> &str {
+    let mut len = 0;
+    while len < dev_name.len() && dev_name[len] != 0 {
+        len += 1;
+    }
+    match core::str::from_utf8(dev_name.split_at(len).0) {
+        Ok(name) => name,
+        Err(_) => panic!("device name isn't UTF-8"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithm::{DEVICE_NAME, DEVICE_SIZE, ERASE_SIZE, FLASH_DEVICE};
+    use crate::chip::CHIP;
+    use crate::option_bytes::OPTION_BYTES_DEVICE;
+    use core::mem::size_of;
+
+    /// The `T` at `offset` bytes into `device`, the way a tool parsing DevDscr reads it
+    fn field<T: Copy>(device: &FlashDeviceDescription, offset: usize) -> T {
+        let device = device as *const FlashDeviceDescription as *const u8;
+        unsafe { device.add(offset).cast::<T>().read_unaligned() }
+    }
+
+    /// Check every field of `device` at its offset in the CMSIS layout
+    fn check_layout(
+        device: &FlashDeviceDescription,
+        name: &str,
+        dev_addr: u32,
+        device_size: u32,
+        page_size: u32,
+        sector_size: u32,
+    ) {
+        assert_eq!(field::<u16>(device, 0), 0x0101);
+        let dev_name = field::<[u8; 128]>(device, 2);
+        assert_eq!(&dev_name[..name.len()], name.as_bytes());
+        assert!(dev_name[name.len()..].iter().all(|&b| b == 0));
+        assert_eq!(field::<u16>(device, 130), 1);
+        assert_eq!(field::<u32>(device, 132), dev_addr);
+        assert_eq!(field::<u32>(device, 136), device_size);
+        assert_eq!(field::<u32>(device, 140), page_size);
+        assert_eq!(field::<u32>(device, 144), 0);
+        assert_eq!(field::<u8>(device, 148), 0xff);
+        assert_eq!(field::<u32>(device, 152), 100);
+        assert_eq!(field::<u32>(device, 156), 6000);
+        // One run of equally sized sectors from the start, then the end marker
+        assert_eq!(field::<[u32; 2]>(device, 160), [sector_size, 0]);
+        assert_eq!(field::<[u32; 2]>(device, 168), [u32::MAX, u32::MAX]);
+        assert_eq!(size_of::<FlashDeviceDescription>(), 160 + 512 * 8);
+    }
+
+    #[test]
+    fn main_flash_is_described() {
+        let page_size = if cfg!(feature = "fast-program") {
+            256
+        } else {
+            1024
+        };
+
+        check_layout(
+            &FLASH_DEVICE,
+            &format!("{} {DEVICE_NAME}", CHIP.name),
+            0x0800_0000,
+            DEVICE_SIZE,
+            page_size,
+            ERASE_SIZE,
+        );
+    }
+
+    #[test]
+    fn option_bytes_are_described() {
+        check_layout(
+            &OPTION_BYTES_DEVICE,
+            &format!("{} option bytes", CHIP.name),
+            0x1fff_f800,
+            16,
+            16,
+            16,
+        );
+    }
+}
@@ -0,0 +1,69 @@
+//! Error codes returned by the flash algorithm functions
+
+use core::num::NonZeroU32;
+
+/// Reasons for a flash algorithm function to fail
+///
+/// Functions return 0 on success and one of these codes otherwise, so any non-zero value is still
+/// a failure for CMSIS tools. [`LastError`] has the details on the latest one.
+#[repr(i32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The controller stayed locked after writing the unlock keys
+    Locked = 1,
+    /// The address is write protected, by WRPR or as reported by WRPRTERR
+    WriteProtected = 2,
+    /// Flash doesn't hold the value that was just programmed
+    Program = 3,
+    /// The controller didn't finish the operation in time
+    Timeout = 4,
+    /// Address or size don't fit the granularity of the operation
+    Misaligned = 5,
+    /// The address lies outside of the flash
+    OutOfRange = 6,
+    /// Flash content differs from what was expected
+    VerifyMismatch = 7,
+    /// Called without a preceding `Init`
+    BadCallOrder = 8,
+    /// `RemoveReadProtection` was called with the wrong key
+    BadKey = 9,
+    /// The host asked for something the loader can't do
+    Unsupported = 10,
+}
+
+/// Return value of a flash algorithm function, 0 on success or the error code
+pub fn status(result: Result<(), Error>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(error) => error as i32,
+    }
+}
+
+/// The error code as the `ErrorCode` of probe-rs' `flash-algorithm` crate
+impl From<Error> for NonZeroU32 {
+    fn from(error: Error) -> Self {
+        // All codes are positive
+        NonZeroU32::new(error as u32).unwrap_or(NonZeroU32::MIN)
+    }
+}
+
+/// Details on the last error
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct LastError {
+    /// [`Error`] code, 0 if nothing failed since `Init`
+    pub code: i32,
+    /// Address the failed operation was working on
+    pub address: u32,
+    /// Raw FLASH_STATR when the error was detected, FMC_STAT0 on the GD32VF103
+    pub statr: u32,
+}
+
+impl LastError {
+    /// Nothing failed
+    pub const NONE: Self = Self {
+        code: 0,
+        address: 0,
+        statr: 0,
+    };
+}
@@ -0,0 +1,259 @@
+//! The GD32VF103, through `gd32vf103_pac`
+//!
+//! Its FMC is laid out like the FLASH controller of the CH32, with the same keys, option bytes
+//! and write protection groups, but 1 KB pages and none of the fast modes. RCU_CTL and RCU_CFG0
+//! match RCC_CTLR and RCC_CFGR0 closely enough for [`clock`], and the free and window watchdogs
+//! the IWDG and WWDG. The time base is the core timer, mtime, instead of SysTick.
+
+use crate::chip::CHIP;
+use crate::clock;
+use crate::hal::{ClockRegisters, FlashController, Mode, WatchdogRegisters};
+use crate::timer;
+use gd32vf103_pac::fmc::RegisterBlock;
+use gd32vf103_pac::{FMC, FWDGT, RCU, WWDGT};
+use riscv::register::mstatus;
+
+/// Flash density in KB, part of the device electronic signature
+const SIG_FLASH_DENSITY: *const u16 = 0x1FFF_F7E0 as *const u16;
+/// Lower half of mtime
+const MTIME_LO: *const u32 = 0xD100_0000 as *const u32;
+/// Upper half of mtime
+const MTIME_HI: *const u32 = 0xD100_0004 as *const u32;
+/// Timer control, bit 0 pauses mtime
+const MSTOP: *mut u32 = 0xD100_0FF8 as *mut u32;
+
+/// The FMC peripheral and the memory behind it
+pub struct Flash;
+
+impl Flash {
+    fn registers(&self) -> &RegisterBlock {
+        unsafe { &(*FMC::ptr()) }
+    }
+}
+
+/// FMC_CTL0 bit selecting `mode`, 0 for the modes the FMC doesn't have
+const fn mode_bit(mode: Mode) -> u32 {
+    match mode {
+        Mode::Program => 1 << 0,
+        Mode::PageErase => 1 << 1,
+        Mode::MassErase => 1 << 2,
+        Mode::OptionBytesProgram => 1 << 4,
+        Mode::OptionBytesErase => 1 << 5,
+        Mode::FastProgram | Mode::Erase256 | Mode::Erase32k => 0,
+    }
+}
+
+impl FlashController for Flash {
+    fn locked(&self) -> bool {
+        self.registers().ctl0.read().lk().bit_is_set()
+    }
+
+    fn write_key(&mut self, key: u32) {
+        self.registers().key0.write(|w| unsafe { w.bits(key) })
+    }
+
+    /// There is no lock for the fast modes, the features selecting them can't be enabled
+    fn fast_locked(&self) -> bool {
+        false
+    }
+
+    fn write_fast_key(&mut self, _key: u32) {}
+
+    fn option_bytes_unlocked(&self) -> bool {
+        self.registers().ctl0.read().obwen().bit_is_set()
+    }
+
+    fn write_option_bytes_key(&mut self, key: u32) {
+        self.registers().obkey.write(|w| unsafe { w.bits(key) })
+    }
+
+    fn set_mode(&mut self, mode: Mode, enabled: bool) {
+        let bit = mode_bit(mode);
+        self.registers().ctl0.modify(|r, w| unsafe {
+            if enabled {
+                w.bits(r.bits() | bit)
+            } else {
+                w.bits(r.bits() & !bit)
+            }
+        });
+    }
+
+    fn set_address(&mut self, address: u32) {
+        self.registers().addr0.write(|w| unsafe { w.bits(address) });
+    }
+
+    fn start(&mut self) {
+        self.registers().ctl0.modify(|_, w| w.start().set_bit());
+    }
+
+    fn reset_buffer(&mut self) {}
+
+    fn load_buffer(&mut self) {}
+
+    fn busy(&self) -> bool {
+        self.registers().stat0.read().busy().bit_is_set()
+    }
+
+    fn status(&self) -> u32 {
+        self.registers().stat0.read().bits()
+    }
+
+    fn clear_status(&mut self) {
+        // ENDF, WPERR and PGERR are cleared by writing 1. PGERR is set when programming a
+        // half-word that isn't erased, the read back reports that as `Program` already.
+        self.registers()
+            .stat0
+            .write(|w| w.wperr().set_bit().endf().set_bit().pgerr().set_bit());
+    }
+
+    fn write_protected_groups(&self) -> u32 {
+        // FMC_WP mirrors WP0-3, a cleared bit means the 4 KB group is protected
+        !self.registers().wp.read().bits()
+    }
+
+    fn read_protected(&self) -> bool {
+        self.registers().obstat.read().spc().bit_is_set()
+    }
+
+    /// All of the flash is zero-wait, there is no split to select
+    fn sram_code_mode(&self) -> u8 {
+        0
+    }
+
+    fn capacity(&self) -> u32 {
+        let kilobytes = unsafe { SIG_FLASH_DENSITY.read_volatile() };
+        kilobytes as u32 * 1024
+    }
+
+    fn read_u8(&self, address: u32) -> u8 {
+        unsafe { (address as *const u8).read_volatile() }
+    }
+
+    fn read_u16(&self, address: u32) -> u16 {
+        unsafe { (address as *const u16).read_volatile() }
+    }
+
+    fn read_u32(&self, address: u32) -> u32 {
+        unsafe { (address as *const u32).read_volatile() }
+    }
+
+    fn write_u16(&mut self, address: u32, value: u16) {
+        unsafe { (address as *mut u16).write_volatile(value) }
+    }
+
+    fn write_u32(&mut self, address: u32, value: u32) {
+        unsafe { (address as *mut u32).write_volatile(value) }
+    }
+}
+
+/// RCU, together with the FMC, the core timer and the watchdogs
+pub struct Rcu;
+
+impl ClockRegisters for Rcu {
+    /// mtime counts on HCLK/4
+    const TICKS_PER_MS: u64 = CHIP.hclk as u64 / 4 / 1000;
+    /// PLLMF, with its fifth bit apart from the others
+    const CFGR0_PLLMUL: u32 = 0b1111 << 18 | 1 << 29;
+    const PLLMUL: u32 = (CHIP.pll_mul as u32 & 0b1111) << 18 | (CHIP.pll_mul as u32 >> 4) << 29;
+    /// IRC8M/2 feeds the PLL and the flash needs no wait states, there's nothing else to set up
+    type Saved = ();
+
+    fn ctlr(&self) -> u32 {
+        rcu().ctl.read().bits()
+    }
+
+    fn write_ctlr(&mut self, value: u32) {
+        rcu().ctl.write(|w| unsafe { w.bits(value) });
+    }
+
+    fn cfgr0(&self) -> u32 {
+        rcu().cfg0.read().bits()
+    }
+
+    fn write_cfgr0(&mut self, value: u32) {
+        rcu().cfg0.write(|w| unsafe { w.bits(value) });
+    }
+
+    fn flash_ctlr(&self) -> u32 {
+        Flash.registers().ctl0.read().bits()
+    }
+
+    fn write_flash_ctlr(&mut self, value: u32) {
+        Flash.registers().ctl0.write(|w| unsafe { w.bits(value) });
+    }
+
+    fn save(&self) -> Self::Saved {}
+
+    fn prepare(&mut self) {}
+
+    fn restore(&mut self, _saved: &Self::Saved) {}
+
+    /// Let mtime run, in case the application paused it
+    fn start_timer(&mut self) -> u32 {
+        unsafe {
+            let mstop = MSTOP.read_volatile();
+            MSTOP.write_volatile(0);
+            mstop
+        }
+    }
+
+    fn stop_timer(&mut self, control: u32) {
+        unsafe { MSTOP.write_volatile(control) };
+    }
+
+    fn now(&self) -> u64 {
+        unsafe { timer::read(MTIME_LO, MTIME_HI) }
+    }
+
+    fn disable_interrupts(&mut self) -> bool {
+        let mie = mstatus::read().mie();
+        unsafe { mstatus::clear_mie() };
+        mie
+    }
+
+    fn enable_interrupts(&mut self) {
+        unsafe { mstatus::set_mie() };
+    }
+}
+
+impl WatchdogRegisters for Rcu {
+    fn write_iwdg_ctlr(&mut self, key: u32) {
+        let fwdgt = unsafe { &(*FWDGT::ptr()) };
+        fwdgt.ctl.write(|w| unsafe { w.bits(key) });
+    }
+
+    fn wwdg_ctlr(&self) -> u32 {
+        wwdgt().ctl.read().bits()
+    }
+
+    fn write_wwdg_ctlr(&mut self, value: u32) {
+        wwdgt().ctl.write(|w| unsafe { w.bits(value) });
+    }
+
+    fn wwdg_cfgr(&self) -> u32 {
+        wwdgt().cfg.read().bits()
+    }
+}
+
+fn rcu() -> &'static gd32vf103_pac::rcu::RegisterBlock {
+    unsafe { &(*RCU::ptr()) }
+}
+
+fn wwdgt() -> &'static gd32vf103_pac::wwdgt::RegisterBlock {
+    unsafe { &(*WWDGT::ptr()) }
+}
+
+/// RCU, the core timer and the watchdogs, switched by [`clock`]
+pub type Clock = clock::Clock<Rcu>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pllmf_is_split_across_cfg0() {
+        // 0b11010, PLLMF[3:0] at bits 21:18 and PLLMF[4] at bit 29
+        assert_eq!(Rcu::PLLMUL, 0b1010 << 18 | 1 << 29);
+        assert_eq!(Rcu::PLLMUL & !Rcu::CFGR0_PLLMUL, 0);
+    }
+}
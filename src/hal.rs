@@ -0,0 +1,169 @@
+//! Hardware the flash algorithms run on
+//!
+//! [`Algorithm`](crate::algorithm::Algorithm) only talks to the chip through these traits. The
+//! loaders use the implementations in `ch32v307` or, with the `gd32vf103` feature, `gd32vf103`,
+//! the tests a simulation.
+
+use crate::error::Error;
+
+/// Operations selected by the mode bits of FLASH_CTLR
+///
+/// The FMC of the GD32VF103 only has PG, PER, MER, OBPG and OBER.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// PG, standard programming of half-words
+    Program,
+    /// FTPG, fast programming of 256 byte pages through the page buffer
+    FastProgram,
+    /// FTER, fast erase of a 256 byte page
+    Erase256,
+    /// PER, standard erase of a [`Chip::page_size`](crate::chip::Chip::page_size) page
+    PageErase,
+    /// BER32, erase of a 32 KB block
+    Erase32k,
+    /// MER, erase of the complete flash
+    MassErase,
+    /// OBPG, programming of an option byte
+    OptionBytesProgram,
+    /// OBER, erase of all option bytes
+    OptionBytesErase,
+}
+
+/// The FLASH controller, together with the memory it programs
+pub trait FlashController {
+    /// CTLR.LOCK, set until [`write_key`](Self::write_key) got both keys
+    fn locked(&self) -> bool;
+    /// Write to KEYR
+    fn write_key(&mut self, key: u32);
+    /// CTLR.FLOCK, the lock of the fast modes
+    fn fast_locked(&self) -> bool;
+    /// Write to MODEKEYR
+    fn write_fast_key(&mut self, key: u32);
+    /// CTLR.OBWRE, set once the option bytes may be written
+    fn option_bytes_unlocked(&self) -> bool;
+    /// Write to OBKEYR
+    fn write_option_bytes_key(&mut self, key: u32);
+
+    /// Set or clear the CTLR bit for `mode`
+    fn set_mode(&mut self, mode: Mode, enabled: bool);
+    /// Write to ADDR, the target of erase and fast programming operations
+    fn set_address(&mut self, address: u32);
+    /// CTLR.STRT, start the operation of the current mode
+    fn start(&mut self);
+    /// CTLR.BUFRST, clear the fast programming page buffer
+    fn reset_buffer(&mut self);
+    /// CTLR.BUFLOAD, take the four words last written into the page buffer
+    fn load_buffer(&mut self);
+
+    /// STATR.BSY
+    fn busy(&self) -> bool;
+    /// Raw STATR, for error reports
+    fn status(&self) -> u32;
+    /// Clear STATR.EOP and STATR.WRPRTERR
+    fn clear_status(&mut self);
+
+    /// Bitmap of the write protected groups, the inverse of WPR
+    fn write_protected_groups(&self) -> u32;
+    /// OBR.RDPRT
+    fn read_protected(&self) -> bool;
+    /// OBR.SRAM_CODE_MODE
+    fn sram_code_mode(&self) -> u8;
+    /// Flash capacity in bytes from the electronic signature
+    fn capacity(&self) -> u32;
+
+    fn read_u8(&self, address: u32) -> u8;
+    fn read_u16(&self, address: u32) -> u16;
+    fn read_u32(&self, address: u32) -> u32;
+    fn write_u16(&mut self, address: u32, value: u16);
+    fn write_u32(&mut self, address: u32, value: u32);
+}
+
+/// Clocks, time base and watchdogs
+pub trait ClockController {
+    /// Ticks of [`now`](Self::now) per millisecond, once [`init`](Self::init) has run
+    const TICKS_PER_MS: u64;
+
+    /// Save the clock state and switch to the clock flash operations are timed for
+    fn init(&mut self) -> Result<(), Error>;
+    /// Restore what [`init`](Self::init) saved, fails without a matching call to it
+    fn uninit(&mut self) -> Result<(), Error>;
+    /// Check if [`init`](Self::init) ran without a matching [`uninit`](Self::uninit) yet
+    fn initialized(&self) -> bool;
+
+    /// Current time in ticks
+    fn now(&self) -> u64;
+    /// Reload the watchdogs the application started
+    fn feed_watchdog(&mut self);
+
+    /// Time in ticks `ms` milliseconds from now
+    fn deadline(&self, ms: u32) -> u64 {
+        self.now() + ms as u64 * Self::TICKS_PER_MS
+    }
+
+    fn expired(&self, deadline: u64) -> bool {
+        self.now() >= deadline
+    }
+}
+
+/// The registers [`watchdog::feed`](crate::watchdog::feed) reloads the watchdogs with
+///
+/// The IWDG and WWDG of the CH32 are laid out like the FWDGT and WWDGT of the GD32VF103.
+pub trait WatchdogRegisters {
+    /// Write the key register of the independent watchdog
+    fn write_iwdg_ctlr(&mut self, key: u32);
+    /// Raw control register of the window watchdog, its enable and counter
+    fn wwdg_ctlr(&self) -> u32;
+    fn write_wwdg_ctlr(&mut self, value: u32);
+    /// Raw configuration register of the window watchdog, with the window
+    fn wwdg_cfgr(&self) -> u32;
+}
+
+/// The registers [`Clock`](crate::clock::Clock) switches the clocks with
+///
+/// RCC_CTLR and RCC_CFGR0 of the CH32 share the layout of the bits involved with RCU_CTL and
+/// RCU_CFG0 of the GD32VF103. What else needs saving or setting up differs between the chips.
+/// The watchdogs are fed while waiting for the clocks.
+pub trait ClockRegisters: WatchdogRegisters {
+    /// Ticks of [`now`](Self::now) per millisecond with HCLK at
+    /// [`Chip::hclk`](crate::chip::Chip::hclk)
+    const TICKS_PER_MS: u64;
+    /// CFGR0 bits holding the PLL multiplier
+    const CFGR0_PLLMUL: u32;
+    /// [`Chip::pll_mul`](crate::chip::Chip::pll_mul) placed in
+    /// [`CFGR0_PLLMUL`](Self::CFGR0_PLLMUL)
+    const PLLMUL: u32;
+    /// Chip specific registers captured by [`save`](Self::save)
+    type Saved: Copy;
+
+    /// Raw CTLR, oscillator and PLL enables and their ready flags
+    fn ctlr(&self) -> u32;
+    fn write_ctlr(&mut self, value: u32);
+    /// Raw CFGR0, clock switch, prescalers and PLL configuration
+    fn cfgr0(&self) -> u32;
+    fn write_cfgr0(&mut self, value: u32);
+    /// Raw CTLR of the flash controller, with its locks
+    fn flash_ctlr(&self) -> u32;
+    fn write_flash_ctlr(&mut self, value: u32);
+
+    /// Capture the registers [`prepare`](Self::prepare) changes
+    fn save(&self) -> Self::Saved;
+    /// Set up what else HCLK needs, called while the core runs from HSI and the PLL is off
+    fn prepare(&mut self);
+    /// Write back what [`save`](Self::save) captured, called like [`prepare`](Self::prepare)
+    fn restore(&mut self, saved: &Self::Saved);
+
+    /// Let the time base run
+    ///
+    /// `Return` - the previous content of its control register, to be handed to
+    /// [`stop_timer`](Self::stop_timer).
+    fn start_timer(&mut self) -> u32;
+    /// Put back the control register value returned by [`start_timer`](Self::start_timer)
+    fn stop_timer(&mut self, control: u32);
+    /// Current time in ticks
+    fn now(&self) -> u64;
+
+    /// Clear mstatus.MIE, `Return` - whether it was set
+    fn disable_interrupts(&mut self) -> bool;
+    /// Set mstatus.MIE
+    fn enable_interrupts(&mut self);
+}
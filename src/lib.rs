@@ -0,0 +1,29 @@
+#![cfg_attr(not(test), no_std)]
+//! Parts shared by the CH32V307 flash loaders, which also serve the rest of the family
+//!
+//! Each loader binary exports the CMSIS flash algorithm functions for one memory region and
+//! builds its `FlashDevice` description from [`device`]. The functions themselves are methods of
+//! [`algorithm::Algorithm`], which reaches the chip through the traits in [`hal`]. On the target
+//! that's `ch32v307`, in the tests a simulation, so `cargo test` runs on the host. [`chip`]
+//! has the parameters of the part the loaders are built for.
+//!
+//! With the `gd32vf103` feature the loaders are built for the GD32VF103 instead. `gd32vf103`
+//! takes the place of `ch32v307`, so only one of the two PACs is linked. Both run the clock
+//! switch, timeouts and watchdogs through [`clock`], [`timer`] and [`watchdog`].
+
+pub mod algorithm;
+#[cfg(not(feature = "gd32vf103"))]
+pub mod ch32v307;
+pub mod chip;
+pub mod clock;
+pub mod controller;
+pub mod device;
+pub mod error;
+#[cfg(feature = "gd32vf103")]
+pub mod gd32vf103;
+pub mod hal;
+pub mod option_bytes;
+#[cfg(test)]
+mod sim;
+pub mod timer;
+pub mod watchdog;
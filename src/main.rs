@@ -6,9 +6,41 @@
 //
 // [ARM CMSIS-Pack documentation]: https://arm-software.github.io/CMSIS_5/Pack/html/algorithmFunc.html
 
+use ch32v307_flashloader::algorithm::Algorithm;
+#[cfg(not(feature = "flash-algorithm"))]
+use ch32v307_flashloader::algorithm::FLASH_DEVICE;
+#[cfg(feature = "flash-algorithm")]
+use ch32v307_flashloader::algorithm::{
+    DEVICE_SIZE, DEV_NAME, EMPTY, ERASE_SIZE, FLASH_BASE, PAGE_SIZE,
+};
+#[cfg(not(feature = "gd32vf103"))]
+use ch32v307_flashloader::ch32v307::{Clock, Flash, Rcc};
+#[cfg(feature = "flash-algorithm")]
+use ch32v307_flashloader::controller::{ERASE_TIME_OUT, PROGRAM_TIME_OUT};
+#[cfg(feature = "flash-algorithm")]
+use ch32v307_flashloader::device::dev_name_str;
+#[cfg(not(feature = "flash-algorithm"))]
+use ch32v307_flashloader::device::FlashDeviceDescription;
+#[cfg(feature = "flash-algorithm")]
+use ch32v307_flashloader::error::Error;
+use ch32v307_flashloader::error::{status, LastError};
+#[cfg(feature = "gd32vf103")]
+use ch32v307_flashloader::gd32vf103::{Clock, Flash, Rcu as Rcc};
+use core::ptr::addr_of_mut;
+#[cfg(not(feature = "flash-algorithm"))]
 use core::slice;
-use ch32v307_pac::{FLASH, RCC};
+#[cfg(feature = "flash-algorithm")]
+use flash_algorithm::{algorithm, ErrorCode, FlashAlgorithm, Function};
+
+// A panic would leave the loader spinning until the host times out. Release builds must not
+// contain any, panic-never turns every panic that survives optimization into a link error.
+// Without optimizations the bounds checks stay, so debug builds abort instead. The `Init`
+// generated by flash-algorithm panics on an unknown function code, so with it release builds
+// abort as well.
+#[cfg(any(debug_assertions, feature = "flash-algorithm"))]
 use panic_abort as _;
+#[cfg(not(any(debug_assertions, feature = "flash-algorithm")))]
+use panic_never as _;
 
 /// Segger tools require the PrgData section to exist in the target binary
 ///
@@ -20,40 +52,76 @@ use panic_abort as _;
 #[link_section = "PrgData"]
 pub static PRGDATA_Start: usize = 0;
 
+/// State kept between the calls, the linker script places it in PrgData
+static mut ALGORITHM: Algorithm<Flash, Clock> = Algorithm::new(Flash, Clock::new(Rcc));
+
+fn algorithm() -> &'static mut Algorithm<Flash, Clock> {
+    // The functions are called one at a time by the debug probe
+    unsafe { &mut *addr_of_mut!(ALGORITHM) }
+}
+
 /// Erase the sector at the given address in flash
 ///
-/// `Return` - 0 on success, 1 on failure.
+/// The sector size is selected at build time, see `algorithm::ERASE_SIZE`.
+///
+/// `Return` - 0 on success, an error code otherwise.
+#[cfg(not(feature = "flash-algorithm"))]
 #[no_mangle]
 #[inline(never)]
 pub extern "C" fn EraseSector(adr: u32) -> i32 {
+    status(algorithm().erase_sector(adr))
+}
 
-    // TODO: Code UnInit for CH32V307
+/// Erase the complete flash with a mass erase
+///
+/// `Return` - 0 on success, an error code otherwise.
+#[cfg(not(feature = "flash-algorithm"))]
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn EraseChip() -> i32 {
+    status(algorithm().erase_chip())
+}
 
-    // ----- [vvv] Example for GD32VF103 -----
-    let fmc = unsafe { &(*FMC::ptr()) };
-    // Enable "Page erase"
-    fmc.ctl0.write_with_zero(|w| w.per().set_bit());
-    // Sector address
-    fmc.addr0.write(|w| unsafe { w.addr().bits(adr) });
-    // Start erase operation
-    fmc.ctl0.modify(|_, w| w.start().set_bit());
-    while fmc.stat0.read().busy().bit_is_set() {
-        // TODO: feed watchdog
-    }
-    // Disable "Page erase"
-    fmc.ctl0.write_with_zero(|w| w.per().clear_bit());
+/// Report whether read-out protection is active
+///
+/// `Return` - 1 if FLASH_OBR.RDPRT is set, 0 otherwise.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn ReadProtection() -> i32 {
+    algorithm().read_protection() as i32
+}
 
-    // On error, clear all the bits except for the error bits and return failure
-    if fmc.stat0.read().pgerr().bit_is_set() || fmc.stat0.read().wperr().bit_is_set() {
-        fmc.stat0
-            .write_with_zero(|w| w.pgerr().set_bit().wperr().set_bit());
-        1
-    } else {
-        0
-    }
+/// Remove read-out protection, which erases the complete flash
+///
+/// Erasing the option bytes of a protected chip makes it mass erase the main flash, after which
+/// RDPR is set to the unprotect key. USER and DATA0/1 are written back, WRPR stays erased, which
+/// leaves no page write protected. The new protection state takes effect after the next reset.
+///
+/// To keep this apart from normal erasing, nothing happens unless `key` is "RDPR" in ASCII,
+/// `0x52445052`. Has to be called between `Init` and `UnInit`.
+///
+/// `Return` - 0 on success or if the chip wasn't protected, `BadKey` for any other `key`, another
+/// error code otherwise.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn RemoveReadProtection(key: u32) -> i32 {
+    status(algorithm().remove_read_protection(key))
+}
+
+/// Check if `sz` bytes of flash starting at `adr` are erased
+///
+/// Every byte is compared with `FlashDevice.empty`, so `pat` is ignored.
+///
+/// `Return` - 0 if the range is blank, `VerifyMismatch` otherwise. `GetLastError` has
+/// the address of the first byte that isn't. A range reaching outside of the flash is rejected
+/// with `OutOfRange`.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn BlankCheck(adr: u32, sz: u32, _pat: u8) -> i32 {
+    status(algorithm().blank_check(adr, sz))
 }
 
-/// Initializes the microcontroller for Flash programming. Returns 0 on Success, 1 otherwise
+/// Initializes the microcontroller for Flash programming. Returns 0 on Success, an error code otherwise
 ///
 /// This is invoked whenever an attempt is made to download the program to Flash.
 ///
@@ -64,196 +132,160 @@ pub extern "C" fn EraseSector(adr: u32) -> i32 {
 /// `clk` - specifies the clock frequency for prgramming the device.
 ///
 /// `fnc` - is a number: 1=Erase, 2=Program, 3=Verify, to perform different init based on command
+#[cfg(not(feature = "flash-algorithm"))]
 #[no_mangle]
 #[inline(never)]
 pub extern "C" fn Init(_adr: u32, _clk: u32, _fnc: u32) -> i32 {
-    // C firmware saved the state of the clock and flash controller to static variables.
-    // We're going to leave the clocks set up set the flash back to reset values on exit instead
-    // Maybe deal with that later.
-    let rcc = unsafe { &(*RCC::ptr()) };
-    let flash = unsafe{ &(*FLASH::ptr()) };
-
-    // init PLL to 96MHz (maximum FLASH operation frequency is 100 MHz)
-
-    // TODO: Code UnInit for CH32V307
-
-    // ----- [vvv] Example for GD32VF103 -----
-    let rcu = unsafe { &(*RCU::ptr()) };
-    let fmc = unsafe { &(*FMC::ptr()) };
-
-    // init PLL to 108MHz
-    rcu.ctl.modify(|_, w| w.irc8men().set_bit()); // enable IRC8 clock
-    while rcu.ctl.read().irc8mstb().bit_is_clear() {} // wait till clock is stable
-    rcu.cfg0.modify(|_, w| unsafe { w.scs().bits(0b00) }); // set IRC8M as CK_SYS source
-    while rcu.cfg0.read().scss().bits() != 0b00 {} // wait till clock has been selected
-    rcu.ctl.modify(|_, w| w.pllen().clear_bit()); // disable PLL
-    rcu.cfg0.modify(|_, w| unsafe {
-        w.ahbpsc().bits(0b0000); // set AHB prescaler to 1
-        w.apb1psc().bits(0b100); // set APB1 prescaler to 2
-        w.apb2psc().bits(0b000); // set APB2 prescaler to 1
-        w.pllsel().clear_bit(); // use IRC8M/2 as PLL input
-        w.pllmf_4().set_bit(); // set multiplier to 27 (0b11010)
-        w.pllmf_3_0().bits(0b1010); // lower bits of multiplier
-        w
-    });
-    rcu.ctl.modify(|_, w| w.pllen().set_bit()); // enable PLL
-    while rcu.ctl.read().pllstb().bit_is_clear() {} // wait until PLL is stable
-    rcu.cfg0.modify(|_, w| unsafe { w.scs().bits(0b10) }); // set PLL as CK_SYS source
-    while rcu.cfg0.read().scss().bits() != 0b10 {} // wait until clock has been selected
-
-    // Unlock flash bank 0
-    if fmc.ctl0.read().lk().bit_is_set() {
-        const FLASH_KEY1: u32 = 0x45670123;
-        const FLASH_KEY2: u32 = 0xCDEF89AB;
-
-        for key in [FLASH_KEY1, FLASH_KEY2] {
-            fmc.key0.write(|w| unsafe { w.bits(key) })
-        }
-    }
-
-    0
+    status(algorithm().init())
 }
 
+/// Report which parts of the flash the WRPR option bytes lock
+///
+/// Bit n stands for the 4 KB at `0x08000000 + n * 0x1000` for n up to 30, bit 31 for all flash
+/// from `0x0801F000` on.
+///
+/// `Return` - bitmap with a 1 for each write protected group.
 #[no_mangle]
 #[inline(never)]
-pub extern "C" fn ProgramPage(adr: u32, sz: u32, buf: *const u8) -> i32 {
-
-    // TODO: Code UnInit for CH32V307
+pub extern "C" fn WriteProtectedGroups() -> u32 {
+    algorithm().write_protected_groups()
+}
 
-    // ----- [vvv] Example for GD32VF103 -----    
+/// Program `sz` bytes from `buf` into flash at `adr`
+///
+/// Uses standard programming mode, which only accepts half-word writes, or with the
+/// `fast-program` feature, fast programming of whole 256 byte pages. `adr` has to be aligned
+/// accordingly. `sz` may be anything, the last half-word or page is padded with
+/// `FlashDevice.empty`, and `buf` needn't be aligned at all.
+///
+/// `Return` - 0 on success, `Misaligned` for a misaligned `adr`, another error code otherwise.
+///
+/// # Safety
+///
+/// `buf` must point to `sz` readable bytes. The debug probe guarantees that.
+#[cfg(not(feature = "flash-algorithm"))]
+#[no_mangle]
+#[inline(never)]
+pub unsafe extern "C" fn ProgramPage(adr: u32, sz: u32, buf: *const u8) -> i32 {
+    let data = slice::from_raw_parts(buf, sz as usize);
 
-    let fmc = unsafe { &(*FMC::ptr()) };
-    // Set page write
-    fmc.ctl0.write_with_zero(|w| w.pg().set_bit());
-    // Should we assume usize programming?
-    // It's what the C code did, its fast and may be required, but oh-so-unsafe...
-    let adr = adr as usize;
-    let sz = sz as usize;
-    let sz_usize = sz >> 2; // u32 = 4 bytes, right-shift by 2 is equivalent
-    let buf_usize = buf as *const usize;
-    // At least get one bit of usability out of rust.
-    // Trying to avoid the provenance debate on destination by constructing pointers from integers
-    let src_slice = unsafe { slice::from_raw_parts(buf_usize, sz_usize) };
-    for (offset, item) in src_slice.iter().enumerate().take(sz) {
-        let dst = (adr + offset * 4) as *mut usize;
+    status(algorithm().program_page(adr, data))
+}
 
-        unsafe { dst.write_volatile(*item) };
-        while fmc.stat0.read().busy().bit_is_set() {
-            // TODO: feed watchdog
-        }
-        // If there's a programming error or write-protect error
-        if fmc.stat0.read().pgerr().bit_is_set() || fmc.stat0.read().wperr().bit_is_set() {
-            // Lock flash
-            fmc.key0.write(|w| unsafe { w.bits(0) });
-            return 1;
-        }
-    }
+/// Compare `sz` bytes of flash at `adr` with the content of `buf`
+///
+/// `Return` - `adr + sz` if the contents match, otherwise the address of the first mismatch,
+/// which is also recorded as `VerifyMismatch` for `GetLastError`.
+///
+/// # Safety
+///
+/// `buf` must point to `sz` readable bytes. The debug probe guarantees that.
+#[cfg(not(feature = "flash-algorithm"))]
+#[no_mangle]
+#[inline(never)]
+pub unsafe extern "C" fn Verify(adr: u32, sz: u32, buf: *const u8) -> u32 {
+    let expected = slice::from_raw_parts(buf, sz as usize);
 
-    0
+    algorithm().verify(adr, expected)
 }
 
-/// De-initializes the microcontroller after Flash programming. Returns 0 on Success, 1 otherwise
+/// De-initializes the microcontroller after Flash programming. Returns 0 on Success, an error code otherwise
 ///
 /// This is invoked at the end of an erasing, programming, or verifying step.
 ///
 ///  # Arguments
 ///
 /// `fnc` - is a number: 1=Erase, 2=Program, 3=Verify, to perform different de-init based on command
+#[cfg(not(feature = "flash-algorithm"))]
 #[no_mangle]
 #[inline(never)]
 pub extern "C" fn UnInit(_fnc: u32) -> i32 {
-
-    // TODO: Code UnInit for CH32V307
-
-    // ----- [vvv] Example for GD32VF103 -----
-
-    let fmc = unsafe { &(*FMC::ptr()) };
-
-    // We could de-initialize, but it's a lot of work.
-    // Let's leave the clocks alone and only reset the flash controller.
-    // Hopefully that's enough.
-    fmc.ctl0.reset();
-    0
+    status(algorithm().uninit())
 }
 
-const fn sectors() -> [FlashSector; 512] {
-    let mut sectors = [FlashSector::default(); 512];
-
-    // 1KB sectors starting at address 0
-    sectors[0] = FlashSector {
-        size: 0x0400,
-        address: 0x0,
-    };
-    sectors[1] = SECTOR_END;
-
-    sectors
+/// Details on the last failure of any of the functions above
+///
+/// `Return` - the address of a [`LastError`], with the error code, the address that was being
+/// worked on and a snapshot of FLASH_STATR. The code is 0 if nothing failed since `Init`.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn GetLastError() -> *const LastError {
+    algorithm().last_error()
 }
 
+#[cfg(not(feature = "flash-algorithm"))]
 #[allow(non_upper_case_globals)]
 #[no_mangle]
 #[link_section = "DeviceData"]
-pub static FlashDevice: FlashDeviceDescription = FlashDeviceDescription {
+pub static FlashDevice: FlashDeviceDescription = FLASH_DEVICE;
 
-    // ToDo: 
+/// Handle on [`ALGORITHM`] for the functions generated by `algorithm!`
+///
+/// The macro creates one in `Init` and drops it in `UnInit`, only in between are the other
+/// generated functions run. The state stays in [`ALGORITHM`], so `BlankCheck`, `GetLastError`
+/// and the other functions above work the same way with either set of exports.
+#[cfg(feature = "flash-algorithm")]
+struct Loader;
+
+#[cfg(feature = "flash-algorithm")]
+impl FlashAlgorithm for Loader {
+    fn new(_address: u32, _clock: u32, _function: Function) -> Result<Self, ErrorCode> {
+        // A failed `init` hands back the clocks itself, there is no `Loader` to drop
+        algorithm().init()?;
+        Ok(Loader)
+    }
 
-    // ----- [vvv] Example for GD32VF103 ----
-    vers: 0x0101,
-    // dev_name: "GD32VF103 128 KB internal flash"
-    dev_name: [
-        // These rows have 12 entries, 12x3 = 36 bytes - need 92 more
-        0x47, 0x44, 0x33, 0x32, 0x56, 0x46, 0x31, 0x30, 0x33, 0x20, 0x31, 0x32, 0x38, 0x20, 0x4b,
-        0x42, 0x20, 0x69, 0x6e, 0x74, 0x65, 0x72, 0x6e, 0x61, 0x6c, 0x20, 0x66, 0x6c, 0x61, 0x73,
-        0x68, 0x00, 0x00, 0x00, 0x00, 0x00,
-        // These are 36 entries each, 36x2 = 72, need 20 more
-        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // and here are those 20
-        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-    ],
-    dev_type: 1,
-    dev_addr: 0x08000000,
-    device_size: 0x00020000,
-    page_size: 1024,
-    _reserved: 0,
-    empty: 0xff,
-    program_time_out: 100,
-    erase_time_out: 6000,
-    flash_sectors: sectors(),
-};
+    fn erase_all(&mut self) -> Result<(), ErrorCode> {
+        algorithm().erase_chip().map_err(Into::into)
+    }
 
-#[repr(C)]
-pub struct FlashDeviceDescription {
-    vers: u16,
-    dev_name: [u8; 128],
-    dev_type: u16,
-    dev_addr: u32,
-    device_size: u32,
-    page_size: u32,
-    _reserved: u32,
-    empty: u8,
-    program_time_out: u32,
-    erase_time_out: u32,
+    fn erase_sector(&mut self, address: u32) -> Result<(), ErrorCode> {
+        algorithm().erase_sector(address).map_err(Into::into)
+    }
 
-    flash_sectors: [FlashSector; 512],
-}
+    fn program_page(&mut self, address: u32, data: &[u8]) -> Result<(), ErrorCode> {
+        algorithm().program_page(address, data).map_err(Into::into)
+    }
+
+    /// Unlike the CMSIS `Verify`, the generated one returns 0 on success or the error code
+    ///
+    /// probe-rs always passes the data. Without it there is nothing to compare with, which is
+    /// reported as `Unsupported` rather than as a mismatch.
+    fn verify(&mut self, address: u32, size: u32, data: Option<&[u8]>) -> Result<(), ErrorCode> {
+        let Some(expected) = data else {
+            return Err(Error::Unsupported.into());
+        };
+        if algorithm().verify(address, expected) != address.wrapping_add(size) {
+            return Err(Error::VerifyMismatch.into());
+        }
+        Ok(())
+    }
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-struct FlashSector {
-    size: u32,
-    address: u32,
+    fn read_flash(&mut self, address: u32, data: &mut [u8]) -> Result<(), ErrorCode> {
+        algorithm().read(address, data).map_err(Into::into)
+    }
 }
 
-impl FlashSector {
-    const fn default() -> Self {
-        FlashSector {
-            size: 0,
-            address: 0,
-        }
+#[cfg(feature = "flash-algorithm")]
+impl Drop for Loader {
+    /// `UnInit`, the generated function reports success regardless
+    fn drop(&mut self) {
+        let _ = algorithm().uninit();
     }
 }
 
-const SECTOR_END: FlashSector = FlashSector {
-    size: 0xffff_ffff,
-    address: 0xffff_ffff,
-};
+#[cfg(feature = "flash-algorithm")]
+algorithm!(Loader, {
+    device_name: dev_name_str(&DEV_NAME),
+    device_type: DeviceType::Onchip,
+    flash_address: FLASH_BASE,
+    flash_size: DEVICE_SIZE,
+    page_size: PAGE_SIZE,
+    empty_value: EMPTY,
+    program_time_out: PROGRAM_TIME_OUT,
+    erase_time_out: ERASE_TIME_OUT,
+    sectors: [{
+        size: ERASE_SIZE,
+        address: 0x0,
+    }]
+});
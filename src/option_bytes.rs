@@ -0,0 +1,220 @@
+//! Option bytes and read-out protection
+//!
+//! The option bytes are eight half-words at [`OB_BASE`]: RDPR, USER, DATA0, DATA1 and WRPR0-3.
+//! The low byte of each half-word holds the value, the high byte its complement.
+
+use crate::algorithm::{Algorithm, CHIP_ERASE_TIME_OUT, EMPTY};
+use crate::chip::CHIP;
+use crate::controller::{
+    ERASE_TIME_OUT, OB_BASE, OB_DATA0, OB_DATA1, OB_RDPR, OB_SIZE, OB_USER, PROGRAM_TIME_OUT,
+    RDPR_UNPROTECTED,
+};
+use crate::device::{dev_name, uniform_sectors, FlashDeviceDescription, ONCHIP, VERSION};
+use crate::error::Error;
+use crate::hal::{ClockController, FlashController, Mode};
+
+/// Key [`Algorithm::remove_read_protection`] has to be called with, "RDPR" in ASCII
+pub const UNPROTECT_KEY: u32 = 0x5244_5052;
+
+/// `FlashDevice` of the option byte loader
+pub const OPTION_BYTES_DEVICE: FlashDeviceDescription = FlashDeviceDescription {
+    vers: VERSION,
+    dev_name: dev_name(&[CHIP.name, " option bytes"]),
+    dev_type: ONCHIP,
+    dev_addr: OB_BASE,
+    device_size: OB_SIZE,
+    page_size: OB_SIZE,
+    _reserved: 0,
+    empty: EMPTY,
+    program_time_out: PROGRAM_TIME_OUT,
+    erase_time_out: ERASE_TIME_OUT,
+    // All option bytes are erased together
+    flash_sectors: uniform_sectors(OB_SIZE),
+};
+
+impl<F: FlashController, C: ClockController> Algorithm<F, C> {
+    /// Set up clocks and controller for writing the option bytes
+    pub fn init_option_bytes(&mut self) -> Result<(), Error> {
+        self.init_with(|algorithm| {
+            algorithm.unlock()?;
+            algorithm.unlock_option_bytes()
+        })
+    }
+
+    /// Erase all option bytes, `adr` has to be [`OB_BASE`]
+    ///
+    /// Erased option bytes read as 0xFFFF, which has RDPR enable read protection from the next
+    /// reset on.
+    pub fn erase_option_bytes(&mut self, adr: u32) -> Result<(), Error> {
+        if !self.clock.initialized() {
+            return Err(self.fail(Error::BadCallOrder, adr));
+        }
+        if adr != OB_BASE {
+            return Err(self.fail(Error::OutOfRange, adr));
+        }
+
+        self.run(Mode::OptionBytesErase, adr, ERASE_TIME_OUT)
+    }
+
+    /// Program the option bytes in `data` at `adr`
+    ///
+    /// Only the low byte of every half-word in `data` is used, the complement in the high byte
+    /// is generated. Half-words given as 0xFFFF are left erased.
+    pub fn program_option_bytes(&mut self, adr: u32, data: &[u8]) -> Result<(), Error> {
+        if !self.clock.initialized() {
+            return Err(self.fail(Error::BadCallOrder, adr));
+        }
+        let in_range = match adr.checked_sub(OB_BASE) {
+            Some(offset) => offset <= OB_SIZE && data.len() as u32 <= OB_SIZE - offset,
+            None => false,
+        };
+        if !in_range {
+            return Err(self.fail(Error::OutOfRange, adr));
+        }
+        // Option bytes are programmed as whole half-words
+        if adr & 1 != 0 || data.len() & 1 != 0 {
+            return Err(self.fail(Error::Misaligned, adr));
+        }
+
+        for (offset, half_word) in data.chunks_exact(2).enumerate() {
+            match *half_word {
+                [0xff, 0xff] => (),
+                [value, _] => self.program_option_byte(adr + offset as u32 * 2, value)?,
+                // chunks_exact only yields pairs, matching instead of indexing keeps this
+                // free of bounds checks
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check whether read-out protection is active
+    pub fn read_protection(&self) -> bool {
+        self.flash.read_protected()
+    }
+
+    /// Remove read-out protection, which erases the complete flash
+    ///
+    /// Erasing the option bytes of a protected chip makes it mass erase the main flash, after
+    /// which RDPR is set to the unprotect key. USER and DATA0/1 are written back, WRPR stays
+    /// erased, which leaves no page write protected. Nothing happens unless the chip is
+    /// protected, and a `key` other than [`UNPROTECT_KEY`] fails with [`Error::BadKey`].
+    pub fn remove_read_protection(&mut self, key: u32) -> Result<(), Error> {
+        if !self.clock.initialized() {
+            return Err(self.fail(Error::BadCallOrder, OB_BASE));
+        }
+        if key != UNPROTECT_KEY {
+            return Err(self.fail(Error::BadKey, OB_BASE));
+        }
+        if !self.flash.read_protected() {
+            return Ok(());
+        }
+        self.unlock_option_bytes()?;
+        let user = self.flash.read_u8(OB_BASE + OB_USER);
+        let data0 = self.flash.read_u8(OB_BASE + OB_DATA0);
+        let data1 = self.flash.read_u8(OB_BASE + OB_DATA1);
+
+        // This includes the mass erase
+        self.run(Mode::OptionBytesErase, OB_BASE, CHIP_ERASE_TIME_OUT)?;
+
+        for (offset, value) in [
+            (OB_RDPR, RDPR_UNPROTECTED),
+            (OB_USER, user),
+            (OB_DATA0, data0),
+            (OB_DATA1, data1),
+        ] {
+            self.program_option_byte(OB_BASE + offset, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithm::{DEVICE_SIZE, FLASH_BASE};
+    use crate::sim::{SimClock, SimFlash};
+
+    /// Algorithm initialized for the option bytes
+    fn algorithm() -> Algorithm<SimFlash, SimClock> {
+        let mut algorithm = Algorithm::new(SimFlash::new(DEVICE_SIZE), SimClock::new());
+        algorithm.init_option_bytes().unwrap();
+        algorithm
+    }
+
+    #[test]
+    fn complements_are_generated() {
+        let mut algorithm = algorithm();
+        algorithm.erase_option_bytes(OB_BASE).unwrap();
+
+        algorithm
+            .program_option_bytes(OB_BASE, &[0xa5, 0x00, 0xff, 0xff, 0x12, 0x00])
+            .unwrap();
+
+        assert_eq!(
+            algorithm.flash.option_bytes[..6],
+            [0xa5, 0x5a, 0xff, 0xff, 0x12, 0xed]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "without its complement")]
+    fn option_bytes_need_their_complement() {
+        let mut algorithm = algorithm();
+        algorithm.erase_option_bytes(OB_BASE).unwrap();
+
+        algorithm.flash.set_mode(Mode::OptionBytesProgram, true);
+        algorithm.flash.write_u16(OB_BASE + OB_USER, 0x00a5);
+    }
+
+    #[test]
+    fn option_bytes_are_checked() {
+        let mut algorithm = algorithm();
+
+        assert_eq!(
+            algorithm.erase_option_bytes(OB_BASE + 2),
+            Err(Error::OutOfRange)
+        );
+        assert_eq!(
+            algorithm.program_option_bytes(OB_BASE + OB_SIZE, &[0xa5, 0x5a]),
+            Err(Error::OutOfRange)
+        );
+        assert_eq!(
+            algorithm.program_option_bytes(OB_BASE + 1, &[0xa5, 0x5a]),
+            Err(Error::Misaligned)
+        );
+        assert!(algorithm.flash.started.is_empty());
+    }
+
+    #[test]
+    fn read_protection_is_removed() {
+        let mut algorithm = Algorithm::new(SimFlash::new(DEVICE_SIZE), SimClock::new());
+        algorithm.flash.read_protected = true;
+        algorithm.flash.option_bytes[..4].copy_from_slice(&[0x00, 0xff, 0x3c, 0xc3]);
+        algorithm.flash.memory[0] = 0x00;
+        algorithm.init().unwrap();
+
+        algorithm.remove_read_protection(UNPROTECT_KEY).unwrap();
+
+        assert_eq!(
+            algorithm.flash.option_bytes[..4],
+            [RDPR_UNPROTECTED, !RDPR_UNPROTECTED, 0x3c, 0xc3]
+        );
+        assert_eq!(algorithm.blank_check(FLASH_BASE, DEVICE_SIZE), Ok(()));
+    }
+
+    #[test]
+    fn read_protection_needs_the_key() {
+        let mut algorithm = Algorithm::new(SimFlash::new(DEVICE_SIZE), SimClock::new());
+        algorithm.flash.read_protected = true;
+        algorithm.init().unwrap();
+
+        assert_eq!(
+            algorithm.remove_read_protection(!UNPROTECT_KEY),
+            Err(Error::BadKey)
+        );
+        assert!(algorithm.flash.started.is_empty());
+    }
+}
@@ -0,0 +1,685 @@
+//! Simulated hardware for the tests
+//!
+//! [`SimFlash`] behaves like NOR flash behind the CH32V307 controller: programming only clears
+//! bits, erasing sets whole sectors back to [`EMPTY`], and nothing can be changed before the
+//! right keys were written. Words written for fast programming only reach the page buffer with
+//! BUFLOAD after a BUFRST, and STRT takes a buffer filled by exactly 16 BUFLOADs. Misuse of the
+//! controller the real chip would silently ignore panics, so the tests notice it. [`SimRcc`]
+//! does the same for the clock tree.
+
+use crate::algorithm::{EMPTY, FAST_PAGE_SIZE, FLASH_BASE, WRP_GROUP_SIZE};
+use crate::chip::CHIP;
+use crate::clock::{
+    CFGR0_PLLSRC, CFGR0_PLLXTPRE, CFGR0_SW, CFGR0_SWS, CTLR_HSEON, CTLR_HSERDY, CTLR_HSION,
+    CTLR_HSIRDY, CTLR_PLLON, CTLR_PLLRDY, SW_HSI, SW_PLL,
+};
+use crate::controller::{FLASH_KEY1, FLASH_KEY2, OB_BASE, OB_SIZE, STATR_WRPRTERR};
+use crate::error::Error;
+use crate::hal::{ClockController, ClockRegisters, FlashController, Mode, WatchdogRegisters};
+use std::cell::{Cell, RefCell};
+
+/// STATR.BSY
+const STATR_BSY: u32 = 1 << 0;
+/// STATR.EOP
+const STATR_EOP: u32 = 1 << 5;
+
+/// One of the locks opened by writing [`FLASH_KEY1`] and [`FLASH_KEY2`]
+#[derive(Copy, Clone, Debug)]
+struct Lock {
+    locked: bool,
+    /// The first key was written
+    key1: bool,
+    /// A wrong key was written, the lock stays closed until the next reset
+    broken: bool,
+}
+
+impl Lock {
+    const LOCKED: Self = Self {
+        locked: true,
+        key1: false,
+        broken: false,
+    };
+
+    fn write_key(&mut self, key: u32) {
+        if self.broken || !self.locked {
+            return;
+        }
+        match (self.key1, key) {
+            (false, FLASH_KEY1) => self.key1 = true,
+            (true, FLASH_KEY2) => self.locked = false,
+            _ => self.broken = true,
+        }
+    }
+}
+
+/// Register access of the algorithm, in the order [`SimFlash::take_log`] returns them
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// KEYR written
+    Key(u32),
+    /// MODEKEYR written
+    FastKey(u32),
+    /// OBKEYR written
+    OptionBytesKey(u32),
+    /// Mode bit in CTLR set or cleared
+    Mode(Mode, bool),
+    /// ADDR written
+    Address(u32),
+    /// CTLR.STRT
+    Start,
+    /// CTLR.BUFRST
+    ResetBuffer,
+    /// CTLR.BUFLOAD
+    LoadBuffer,
+    /// STATR.BSY polled, with the value read
+    Busy(bool),
+    /// STATR read
+    Status,
+    /// STATR.EOP and STATR.WRPRTERR cleared
+    ClearStatus,
+    /// Half-word written to flash
+    WriteU16(u32, u16),
+    /// Word written to flash
+    WriteU32(u32, u32),
+}
+
+/// FLASH controller and memory of a simulated CH32V307
+pub struct SimFlash {
+    /// Main flash from [`FLASH_BASE`] on
+    pub memory: Vec<u8>,
+    /// The eight option bytes and their complements
+    pub option_bytes: [u8; OB_SIZE as usize],
+    /// ESIG FLACAP, the flash capacity in KB
+    pub flacap: u16,
+    pub sram_code_mode: u8,
+    /// Raw WPR, a cleared bit protects the group
+    pub wpr: u32,
+    pub read_protected: bool,
+    /// Polls of BSY that still read as busy after an operation started, `u32::MAX` never clears
+    pub busy_polls: u32,
+    /// Mode of every operation [`start`](FlashController::start) ran so far
+    pub started: Vec<Mode>,
+
+    lock: Lock,
+    fast_lock: Lock,
+    option_bytes_lock: Lock,
+    mode: Option<Mode>,
+    address: u32,
+    busy: Cell<u32>,
+    statr: u32,
+    buffer: [u32; FAST_PAGE_SIZE / 4],
+    /// BUFLOADs since the last BUFRST, `None` before the first BUFRST and after STRT
+    buffer_loads: Option<usize>,
+    /// Words written since the last BUFLOAD, only BUFLOAD moves them into the buffer
+    latch: [Option<u32>; 4],
+    /// 16 byte block of the buffer the words in `latch` belong to
+    latch_block: usize,
+    log: RefCell<Vec<Access>>,
+}
+
+impl SimFlash {
+    /// Erased flash of `capacity` bytes, as reported by ESIG, without any protection
+    pub fn new(capacity: u32) -> Self {
+        Self {
+            memory: vec![EMPTY; capacity as usize],
+            // RDPR unprotected, everything else erased
+            option_bytes: [
+                0xa5, 0x5a, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                0xff, 0xff,
+            ],
+            flacap: (capacity / 1024) as u16,
+            sram_code_mode: 0b11,
+            wpr: u32::MAX,
+            read_protected: false,
+            busy_polls: 0,
+            started: Vec::new(),
+            lock: Lock::LOCKED,
+            fast_lock: Lock::LOCKED,
+            option_bytes_lock: Lock::LOCKED,
+            mode: None,
+            address: 0,
+            busy: Cell::new(0),
+            statr: 0,
+            buffer: [u32::MAX; FAST_PAGE_SIZE / 4],
+            buffer_loads: None,
+            latch: [None; 4],
+            latch_block: 0,
+            log: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Register accesses since the last call
+    pub fn take_log(&self) -> Vec<Access> {
+        self.log.take()
+    }
+
+    fn record(&self, access: Access) {
+        self.log.borrow_mut().push(access);
+    }
+
+    /// Offset of `address` into [`Self::memory`], if it's in flash
+    fn offset(&self, address: u32) -> Option<usize> {
+        let offset = address.checked_sub(FLASH_BASE)? as usize;
+        (offset < self.memory.len()).then_some(offset)
+    }
+
+    fn protected(&self, offset: usize) -> bool {
+        let group = (offset as u32 / WRP_GROUP_SIZE).min(31);
+        self.wpr & (1 << group) == 0
+    }
+
+    /// Let the current operation take [`Self::busy_polls`], then report its end
+    fn operate(&mut self) {
+        assert!(self.busy.get() == 0, "flash accessed while busy");
+        self.busy.set(self.busy_polls);
+        self.statr |= STATR_EOP;
+    }
+
+    /// Erase `len` bytes of main flash from `offset` on, unless one of them is protected
+    fn erase(&mut self, offset: usize, len: usize) {
+        let range = offset..(offset + len).min(self.memory.len());
+        if range.clone().any(|offset| self.protected(offset)) {
+            self.statr |= STATR_WRPRTERR;
+            return;
+        }
+        self.memory[range].fill(EMPTY);
+    }
+
+    fn erase_sector(&mut self, len: u32) {
+        let address = self.address & !(len - 1);
+        let offset = self.offset(address).expect("erase outside of flash");
+        self.erase(offset, len as usize);
+    }
+}
+
+impl FlashController for SimFlash {
+    fn locked(&self) -> bool {
+        self.lock.locked
+    }
+
+    fn write_key(&mut self, key: u32) {
+        self.record(Access::Key(key));
+        self.lock.write_key(key);
+    }
+
+    fn fast_locked(&self) -> bool {
+        self.fast_lock.locked
+    }
+
+    fn write_fast_key(&mut self, key: u32) {
+        self.record(Access::FastKey(key));
+        assert!(!self.lock.locked, "fast mode keys written while locked");
+        self.fast_lock.write_key(key);
+    }
+
+    fn option_bytes_unlocked(&self) -> bool {
+        !self.option_bytes_lock.locked
+    }
+
+    fn write_option_bytes_key(&mut self, key: u32) {
+        self.record(Access::OptionBytesKey(key));
+        assert!(!self.lock.locked, "option byte keys written while locked");
+        self.option_bytes_lock.write_key(key);
+    }
+
+    fn set_mode(&mut self, mode: Mode, enabled: bool) {
+        self.record(Access::Mode(mode, enabled));
+        assert!(!self.lock.locked, "{mode:?} selected while locked");
+        if !enabled {
+            assert_eq!(self.mode, Some(mode), "{mode:?} cleared but wasn't set");
+            self.mode = None;
+            return;
+        }
+        assert_eq!(self.mode, None, "{mode:?} selected on top of another mode");
+        match mode {
+            Mode::FastProgram | Mode::Erase256 | Mode::Erase32k => {
+                assert!(!self.fast_lock.locked, "{mode:?} selected while locked")
+            }
+            Mode::OptionBytesProgram | Mode::OptionBytesErase => {
+                assert!(
+                    !self.option_bytes_lock.locked,
+                    "{mode:?} selected while locked"
+                )
+            }
+            _ => (),
+        }
+        self.mode = Some(mode);
+    }
+
+    fn set_address(&mut self, address: u32) {
+        self.record(Access::Address(address));
+        self.address = address;
+    }
+
+    fn start(&mut self) {
+        self.record(Access::Start);
+        let mode = self.mode.expect("operation started without a mode");
+        self.started.push(mode);
+        self.operate();
+        match mode {
+            Mode::Erase256 => self.erase_sector(0x100),
+            Mode::PageErase => self.erase_sector(CHIP.page_size),
+            Mode::Erase32k => self.erase_sector(0x8000),
+            Mode::MassErase => {
+                if self.wpr != u32::MAX {
+                    self.statr |= STATR_WRPRTERR;
+                } else {
+                    self.memory.fill(EMPTY);
+                }
+            }
+            Mode::OptionBytesErase => {
+                // Unprotecting a protected chip takes the whole flash with it
+                if self.read_protected {
+                    self.memory.fill(EMPTY);
+                }
+                self.option_bytes.fill(EMPTY);
+            }
+            Mode::FastProgram => {
+                match self.buffer_loads.take() {
+                    Some(loads) => assert!(
+                        loads == FAST_PAGE_SIZE / 16,
+                        "STRT after {loads} of 16 BUFLOADs"
+                    ),
+                    None => panic!("STRT without BUFRST"),
+                }
+                let address = self.address & !(FAST_PAGE_SIZE as u32 - 1);
+                let offset = self
+                    .offset(address)
+                    .expect("fast programming outside of flash");
+                if self.protected(offset) {
+                    self.statr |= STATR_WRPRTERR;
+                    return;
+                }
+                for (index, word) in self.buffer.iter().enumerate() {
+                    for (byte, value) in word.to_le_bytes().iter().enumerate() {
+                        self.memory[offset + index * 4 + byte] &= value;
+                    }
+                }
+            }
+            Mode::Program | Mode::OptionBytesProgram => panic!("STRT set in {mode:?}"),
+        }
+    }
+
+    fn reset_buffer(&mut self) {
+        self.record(Access::ResetBuffer);
+        assert_eq!(
+            self.mode,
+            Some(Mode::FastProgram),
+            "BUFRST outside of fast mode"
+        );
+        self.operate();
+        self.buffer.fill(u32::MAX);
+        self.buffer_loads = Some(0);
+        self.latch = [None; 4];
+    }
+
+    fn load_buffer(&mut self) {
+        self.record(Access::LoadBuffer);
+        assert_eq!(
+            self.mode,
+            Some(Mode::FastProgram),
+            "BUFLOAD outside of fast mode"
+        );
+        let loads = self.buffer_loads.expect("BUFLOAD before BUFRST");
+        let words = self
+            .latch
+            .map(|word| word.expect("BUFLOAD before all four words were written"));
+        let block = self.latch_block * 4;
+        self.buffer[block..block + 4].copy_from_slice(&words);
+        self.latch = [None; 4];
+        self.buffer_loads = Some(loads + 1);
+        self.operate();
+    }
+
+    fn busy(&self) -> bool {
+        let busy = self.busy.get();
+        self.record(Access::Busy(busy != 0));
+        if busy == u32::MAX {
+            return true;
+        }
+        self.busy.set(busy.saturating_sub(1));
+        busy != 0
+    }
+
+    fn status(&self) -> u32 {
+        self.record(Access::Status);
+        if self.busy.get() != 0 {
+            self.statr | STATR_BSY
+        } else {
+            self.statr
+        }
+    }
+
+    fn clear_status(&mut self) {
+        self.record(Access::ClearStatus);
+        self.statr &= !(STATR_EOP | STATR_WRPRTERR);
+    }
+
+    fn write_protected_groups(&self) -> u32 {
+        !self.wpr
+    }
+
+    fn read_protected(&self) -> bool {
+        self.read_protected
+    }
+
+    fn sram_code_mode(&self) -> u8 {
+        self.sram_code_mode
+    }
+
+    fn capacity(&self) -> u32 {
+        self.flacap as u32 * 1024
+    }
+
+    fn read_u8(&self, address: u32) -> u8 {
+        if let Some(offset) = address
+            .checked_sub(OB_BASE)
+            .filter(|&offset| offset < OB_SIZE)
+        {
+            return self.option_bytes[offset as usize];
+        }
+        self.memory[self.offset(address).expect("read outside of flash")]
+    }
+
+    fn read_u16(&self, address: u32) -> u16 {
+        assert!(address & 1 == 0, "misaligned half-word read");
+        u16::from_le_bytes([self.read_u8(address), self.read_u8(address + 1)])
+    }
+
+    fn read_u32(&self, address: u32) -> u32 {
+        assert!(address & 3 == 0, "misaligned word read");
+        u32::from_le_bytes([
+            self.read_u8(address),
+            self.read_u8(address + 1),
+            self.read_u8(address + 2),
+            self.read_u8(address + 3),
+        ])
+    }
+
+    fn write_u16(&mut self, address: u32, value: u16) {
+        self.record(Access::WriteU16(address, value));
+        assert!(address & 1 == 0, "misaligned half-word write");
+        match self.mode {
+            Some(Mode::Program) => {
+                self.operate();
+                let offset = self.offset(address).expect("programming outside of flash");
+                if self.protected(offset) {
+                    self.statr |= STATR_WRPRTERR;
+                    return;
+                }
+                for (byte, value) in value.to_le_bytes().iter().enumerate() {
+                    self.memory[offset + byte] &= value;
+                }
+            }
+            Some(Mode::OptionBytesProgram) => {
+                self.operate();
+                let offset = address
+                    .checked_sub(OB_BASE)
+                    .filter(|&offset| offset < OB_SIZE)
+                    .expect("option byte programming outside of the option bytes");
+                // The chip only flags a mismatch in OBR.OBERR on the next option byte load
+                let [low, high] = value.to_le_bytes();
+                assert_eq!(
+                    high, !low,
+                    "option byte at {address:#x} programmed without its complement"
+                );
+                for (byte, value) in value.to_le_bytes().iter().enumerate() {
+                    self.option_bytes[offset as usize + byte] &= value;
+                }
+            }
+            mode => panic!("half-word written to flash in {mode:?}"),
+        }
+    }
+
+    fn write_u32(&mut self, address: u32, value: u32) {
+        self.record(Access::WriteU32(address, value));
+        assert!(address & 3 == 0, "misaligned word write");
+        assert_eq!(
+            self.mode,
+            Some(Mode::FastProgram),
+            "word written to flash outside of fast mode"
+        );
+        let offset = address as usize % FAST_PAGE_SIZE;
+        if self.latch.iter().any(Option::is_some) {
+            assert_eq!(
+                offset / 16,
+                self.latch_block,
+                "words of two blocks written without BUFLOAD"
+            );
+        }
+        self.latch_block = offset / 16;
+        self.latch[offset % 16 / 4] = Some(value);
+    }
+}
+
+/// Time base and watchdog of a simulated CH32V307
+///
+/// Time passes by one tick, a microsecond, whenever it's read.
+pub struct SimClock {
+    /// Ticks after which the watchdog resets the chip unless it's fed, `None` if it's off
+    pub watchdog: Option<u64>,
+    /// The watchdog wasn't fed in time
+    pub watchdog_reset: Cell<bool>,
+    initialized: bool,
+    ticks: Cell<u64>,
+    fed: Cell<u64>,
+}
+
+impl SimClock {
+    pub fn new() -> Self {
+        Self {
+            watchdog: None,
+            watchdog_reset: Cell::new(false),
+            initialized: false,
+            ticks: Cell::new(0),
+            fed: Cell::new(0),
+        }
+    }
+}
+
+impl ClockController for SimClock {
+    const TICKS_PER_MS: u64 = 1000;
+
+    fn init(&mut self) -> Result<(), Error> {
+        self.initialized = true;
+        Ok(())
+    }
+
+    fn uninit(&mut self) -> Result<(), Error> {
+        if !self.initialized {
+            return Err(Error::BadCallOrder);
+        }
+        self.initialized = false;
+        Ok(())
+    }
+
+    fn initialized(&self) -> bool {
+        self.initialized
+    }
+
+    fn now(&self) -> u64 {
+        let ticks = self.ticks.get() + 1;
+        self.ticks.set(ticks);
+        if let Some(period) = self.watchdog {
+            if ticks - self.fed.get() > period {
+                self.watchdog_reset.set(true);
+            }
+        }
+        ticks
+    }
+
+    fn feed_watchdog(&mut self) {
+        self.fed.set(self.ticks.get());
+    }
+}
+
+/// RCC of a simulated CH32V307, with the registers around it that the clock switch touches
+///
+/// The ready flags follow the enables and SWS follows SW right away, unless [`Self::stuck`] holds
+/// a flag back. Stopping the clock the core runs from, changing the PLL while it runs or
+/// switching to it before it's ready panics. Time passes like for [`SimClock`]. The watchdogs
+/// don't count down, but refreshing the WWDG outside of its window panics.
+pub struct SimRcc {
+    /// CTLR without the ready flags
+    pub ctlr: u32,
+    /// CFGR0 without SWS
+    pub cfgr0: u32,
+    /// Ready flags in CTLR that never get set
+    pub stuck: u32,
+    pub flash_ctlr: u32,
+    pub flash_actlr: u32,
+    /// SysTick control register
+    pub stk_ctlr: u32,
+    /// mstatus.MIE
+    pub mie: bool,
+    /// Writes of the reload key 0xAAAA to IWDG_CTLR
+    pub iwdg_reloads: u32,
+    pub wwdg_ctlr: u32,
+    pub wwdg_cfgr: u32,
+    ticks: Cell<u64>,
+}
+
+impl SimRcc {
+    /// Reset values, the core runs from HSI and the flash controller is locked
+    pub fn new() -> Self {
+        Self {
+            // HSITRIM at its default of 16
+            ctlr: CTLR_HSION | 0x80,
+            cfgr0: 0,
+            stuck: 0,
+            // LOCK and FLOCK
+            flash_ctlr: 0x8080,
+            flash_actlr: 0,
+            stk_ctlr: 0,
+            mie: true,
+            iwdg_reloads: 0,
+            // The WWDG is stopped, counter and window at their maximum
+            wwdg_ctlr: 0x7F,
+            wwdg_cfgr: 0x7F,
+            ticks: Cell::new(0),
+        }
+    }
+}
+
+impl ClockRegisters for SimRcc {
+    const TICKS_PER_MS: u64 = 1000;
+    /// PLLMUL, with the fifth bit the GD32VF103 has at bit 29
+    const CFGR0_PLLMUL: u32 = 0b1111 << 18 | 1 << 29;
+    const PLLMUL: u32 = (CHIP.pll_mul as u32 & 0b1111) << 18 | (CHIP.pll_mul as u32 >> 4) << 29;
+    /// FLASH_ACTLR
+    type Saved = u32;
+
+    fn ctlr(&self) -> u32 {
+        let ready = (self.ctlr & (CTLR_HSION | CTLR_HSEON | CTLR_PLLON)) << 1;
+        self.ctlr | ready & !self.stuck
+    }
+
+    fn write_ctlr(&mut self, value: u32) {
+        let value = value & !(CTLR_HSIRDY | CTLR_HSERDY | CTLR_PLLRDY);
+        match self.cfgr0 & CFGR0_SW {
+            SW_HSI => assert!(
+                value & CTLR_HSION != 0,
+                "HSI stopped while it runs the core"
+            ),
+            SW_PLL => assert!(
+                value & CTLR_PLLON != 0,
+                "PLL stopped while it runs the core"
+            ),
+            _ => (),
+        }
+        self.ctlr = value;
+    }
+
+    fn cfgr0(&self) -> u32 {
+        self.cfgr0 | (self.cfgr0 & CFGR0_SW) << 2
+    }
+
+    fn write_cfgr0(&mut self, value: u32) {
+        let value = value & !CFGR0_SWS;
+        let pll = CFGR0_PLLSRC | CFGR0_PLLXTPRE | Self::CFGR0_PLLMUL;
+        if self.ctlr & CTLR_PLLON != 0 {
+            assert_eq!(value & pll, self.cfgr0 & pll, "PLL changed while it runs");
+        }
+        if value & CFGR0_SW == SW_PLL && self.cfgr0 & CFGR0_SW != SW_PLL {
+            assert!(
+                self.ctlr() & CTLR_PLLRDY != 0,
+                "switched to the PLL before it was ready"
+            );
+        }
+        self.cfgr0 = value;
+    }
+
+    fn flash_ctlr(&self) -> u32 {
+        self.flash_ctlr
+    }
+
+    fn write_flash_ctlr(&mut self, value: u32) {
+        self.flash_ctlr = value;
+    }
+
+    fn save(&self) -> Self::Saved {
+        self.flash_actlr
+    }
+
+    fn prepare(&mut self) {
+        self.flash_actlr = self.flash_actlr & !0b111 | CHIP.latency as u32;
+    }
+
+    fn restore(&mut self, saved: &Self::Saved) {
+        self.flash_actlr = *saved;
+    }
+
+    fn start_timer(&mut self) -> u32 {
+        let stk_ctlr = self.stk_ctlr;
+        // STCLK and STE
+        self.stk_ctlr = 0b101;
+        stk_ctlr
+    }
+
+    fn stop_timer(&mut self, control: u32) {
+        self.stk_ctlr = control;
+    }
+
+    fn now(&self) -> u64 {
+        let ticks = self.ticks.get() + 1;
+        self.ticks.set(ticks);
+        ticks
+    }
+
+    fn disable_interrupts(&mut self) -> bool {
+        let mie = self.mie;
+        self.mie = false;
+        mie
+    }
+
+    fn enable_interrupts(&mut self) {
+        self.mie = true;
+    }
+}
+
+impl WatchdogRegisters for SimRcc {
+    fn write_iwdg_ctlr(&mut self, key: u32) {
+        if key == 0xAAAA {
+            self.iwdg_reloads += 1;
+        }
+    }
+
+    fn wwdg_ctlr(&self) -> u32 {
+        self.wwdg_ctlr
+    }
+
+    fn write_wwdg_ctlr(&mut self, value: u32) {
+        // WDGA, T and W
+        if self.wwdg_ctlr & 1 << 7 != 0 {
+            assert!(
+                self.wwdg_ctlr & 0x7F < self.wwdg_cfgr & 0x7F,
+                "WWDG refreshed before its window opened"
+            );
+        }
+        self.wwdg_ctlr = value;
+    }
+
+    fn wwdg_cfgr(&self) -> u32 {
+        self.wwdg_cfgr
+    }
+}
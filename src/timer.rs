@@ -0,0 +1,20 @@
+//! Core timers, the time base for timeouts
+//!
+//! SysTick of the QingKe core and mtime of the Bumblebee core both count in 64 bits, which the
+//! core reads as two halves.
+
+/// Current count of the timer with its lower half at `low` and its upper half at `high`
+///
+/// # Safety
+///
+/// Both have to be readable registers.
+pub(crate) unsafe fn read(low: *const u32, high: *const u32) -> u64 {
+    loop {
+        let high_before = high.read_volatile();
+        let low = low.read_volatile();
+        // Retry if the lower half wrapped between the two reads
+        if high.read_volatile() == high_before {
+            return (high_before as u64) << 32 | low as u64;
+        }
+    }
+}
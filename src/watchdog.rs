@@ -0,0 +1,81 @@
+//! Reloading the watchdogs while waiting for the flash controller
+//!
+//! Once started by the application, neither watchdog can be stopped until the next reset, and
+//! both keep running while the core is halted by the debugger. Erasing takes long enough for
+//! either of them to reset the chip in the middle of the operation. The chip provides the
+//! registers through [`WatchdogRegisters`].
+
+use crate::hal::WatchdogRegisters;
+
+/// Reload the independent watchdog with the application's RLDR
+const IWDG_RELOAD: u32 = 0xAAAA;
+/// WWDG_CTLR.WDGA, set by software and only cleared by a reset
+const WWDG_CTLR_WDGA: u32 = 1 << 7;
+/// WWDG_CTLR.T, the counter
+const WWDG_CTLR_T: u32 = 0x7F;
+/// WWDG_CFGR.W, the window the counter has to be below for a refresh
+const WWDG_CFGR_W: u32 = 0x7F;
+
+/// Reload the watchdogs the application started
+///
+/// Call this in every loop that waits for the hardware.
+pub fn feed(registers: &mut impl WatchdogRegisters) {
+    // Nothing tells whether the IWDG runs, starting it forces LSI on without setting LSION. The
+    // reload key does nothing to a stopped one.
+    registers.write_iwdg_ctlr(IWDG_RELOAD);
+
+    let ctlr = registers.wwdg_ctlr();
+    // A refresh before the counter dropped below the window resets the chip as well
+    if ctlr & WWDG_CTLR_WDGA != 0 && ctlr & WWDG_CTLR_T < registers.wwdg_cfgr() & WWDG_CFGR_W {
+        registers.write_wwdg_ctlr(WWDG_CTLR_WDGA | WWDG_CTLR_T);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimRcc;
+
+    #[test]
+    fn the_iwdg_is_always_reloaded() {
+        let mut rcc = SimRcc::new();
+
+        feed(&mut rcc);
+
+        assert_eq!(rcc.iwdg_reloads, 1);
+    }
+
+    #[test]
+    fn the_wwdg_is_refreshed_inside_its_window() {
+        let mut rcc = SimRcc::new();
+        rcc.wwdg_ctlr = WWDG_CTLR_WDGA | 0x50;
+        rcc.wwdg_cfgr = 0x60;
+
+        feed(&mut rcc);
+
+        assert_eq!(rcc.wwdg_ctlr, WWDG_CTLR_WDGA | WWDG_CTLR_T);
+    }
+
+    #[test]
+    fn the_wwdg_waits_for_its_window() {
+        let mut rcc = SimRcc::new();
+        rcc.wwdg_ctlr = WWDG_CTLR_WDGA | 0x70;
+        rcc.wwdg_cfgr = 0x60;
+
+        // The simulation panics on a refresh outside of the window
+        feed(&mut rcc);
+
+        assert_eq!(rcc.wwdg_ctlr, WWDG_CTLR_WDGA | 0x70);
+    }
+
+    #[test]
+    fn a_stopped_wwdg_is_left_alone() {
+        let mut rcc = SimRcc::new();
+        rcc.wwdg_ctlr = 0x50;
+        rcc.wwdg_cfgr = 0x60;
+
+        feed(&mut rcc);
+
+        assert_eq!(rcc.wwdg_ctlr, 0x50);
+    }
+}